@@ -1,24 +1,68 @@
 use anyhow::{anyhow, Context, Result};
-use clap::{ArgAction, Parser, ValueEnum};
-use directories::ProjectDirs;
+use chrono::Timelike;
+use clap::{ArgAction, CommandFactory, Parser, Subcommand, ValueEnum};
+use clap_complete::Shell;
+use directories::{BaseDirs, ProjectDirs};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use log::{debug, info};
+use rand::distributions::WeightedIndex;
 use rand::prelude::*;
-use serde::Deserialize;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
 use std::cmp::min;
-use std::ffi::OsStr;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::{OsStr, OsString};
 use std::fs;
 use std::io::{IsTerminal, Read, Write};
 use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::process::{Command, Output, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
 use terminal_size::{terminal_size, Height, Width};
-use textwrap::wrap;
-use unicode_width::UnicodeWidthStr;
+use textwrap::{wrap, Options, WordSeparator, WordSplitter};
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
 use walkdir::WalkDir;
 
 const DEFAULT_MESSAGE: &str = "Hello from leftysay!";
 const DEFAULT_MAX_HEIGHT_RATIO: f32 = 0.55;
 const DEFAULT_BUBBLE_MAX_WIDTH: usize = 60;
+const DEFAULT_BUBBLE_PADDING: usize = 1;
 const DEFAULT_CACHE_MAX_MB: u64 = 64;
-const CACHE_FILE_EXT: &str = "txt";
+const DEFAULT_CACHE_MAX_ENTRIES: usize = 500;
+const DEFAULT_CHAFA_TIMEOUT_MS: u64 = 5000;
+const DEFAULT_HISTORY_SIZE: usize = 3;
+const CHAFA_POLL_INTERVAL: Duration = Duration::from_millis(10);
+const SPINNER_DELAY: Duration = Duration::from_millis(150);
+const SPINNER_FRAMES: [char; 4] = ['|', '/', '-', '\\'];
+const CACHE_FILE_EXT: &str = "txt.gz";
+// Bump when rendering or compose changes make existing cache entries stale.
+const CACHE_SCHEMA_VERSION: u32 = 2;
+const CACHE_VERSION_FILE: &str = "cache-version";
+const CACHE_LOCK_FILE: &str = "cache.lock";
+const CACHE_INDEX_FILE: &str = "cache-index.json";
+const CACHE_HITS_FILE: &str = "cache-hits.log";
+const CACHE_HITS_COMPACT_BYTES: u64 = 64 * 1024;
+const FRAME_FILE_EXT: &str = "frame.png";
+// Where extracted GIF frames were kept before they became cache entries.
+const LEGACY_FRAME_DIR: &str = "frames";
+const REMOTE_IMAGE_DIR: &str = "remote";
+const REMOTE_IMAGE_TIMEOUT: Duration = Duration::from_secs(10);
+const REMOTE_IMAGE_MAX_BYTES: u64 = 32 * 1024 * 1024;
+const PACK_INDEX_FILE: &str = "packs-index.json";
+// How deep below a search path `pack.toml` files are looked for.
+const PACK_DISCOVERY_DEPTH: usize = 3;
+const HISTORY_FILE: &str = "history.json";
+const RANDOM_PACK: &str = "random";
+const ALL_PACKS: &str = "all";
+const MONTAGE_GAP: usize = 2;
+const DIR_PACK_PREFIX: &str = "dir:";
+const DEFAULT_CATEGORY: &str = "default";
 
 #[derive(Parser, Debug)]
 #[command(
@@ -27,27 +71,89 @@ const CACHE_FILE_EXT: &str = "txt";
     about = "A terminal greeter that renders a speech bubble and image via chafa"
 )]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Commands>,
     /// Override message
     #[arg(long)]
     text: Option<String>,
-    /// Render a specific image
+    /// Read the message from a file
+    #[arg(long)]
+    text_file: Option<PathBuf>,
+    /// Pick the message from this messages.txt section
+    #[arg(long)]
+    category: Option<String>,
+    /// Read the message from stdin even when it is a terminal
+    #[arg(long, action = ArgAction::SetTrue)]
+    stdin: bool,
+    /// Render a specific image (a path or an http(s) URL)
     #[arg(long)]
     image: Option<PathBuf>,
-    /// Choose a pack
+    /// Render the image with this file name from the pack
+    #[arg(long)]
+    image_name: Option<String>,
+    /// Render this many distinct pack images side by side
+    #[arg(long, conflicts_with_all = ["image", "image_name"])]
+    count: Option<usize>,
+    /// Choose a pack ("random" picks one of the installed packs, "all" pools them)
     #[arg(long)]
     pack: Option<String>,
     /// List packs and images
     #[arg(long, action = ArgAction::SetTrue)]
     list: bool,
+    /// With --list, show each image's pixel size and file size
+    #[arg(long, action = ArgAction::SetTrue, requires = "list")]
+    details: bool,
+    /// Render fresh and skip writing the render cache for this run
+    #[arg(long, action = ArgAction::SetTrue, conflicts_with = "cache")]
+    no_cache: bool,
+    /// Use the render cache even if disabled in the config
+    #[arg(long, action = ArgAction::SetTrue)]
+    cache: bool,
+    /// Always rescan packs instead of using the cached pack index
+    #[arg(long, action = ArgAction::SetTrue)]
+    no_pack_cache: bool,
+    /// Emit machine-readable JSON (with --list or --doctor)
+    #[arg(long, action = ArgAction::SetTrue)]
+    json: bool,
     /// Diagnostics
     #[arg(long, action = ArgAction::SetTrue)]
     doctor: bool,
+    /// Print the effective configuration, after CLI overrides, as TOML
+    #[arg(long, action = ArgAction::SetTrue)]
+    dump_config: bool,
+    /// Print the chafa command that would run instead of rendering
+    #[arg(long, action = ArgAction::SetTrue)]
+    dry_run: bool,
+    /// Explain decisions on stderr (-vv adds chafa's stderr on failure)
+    #[arg(short, long, action = ArgAction::Count)]
+    verbose: u8,
+    /// Exit silently if chafa is missing or rendering fails
+    #[arg(long, action = ArgAction::SetTrue)]
+    quiet: bool,
+    /// Read config from this file instead of the default location
+    #[arg(long)]
+    config: Option<PathBuf>,
+    /// Apply the [profiles.<name>] table from the config on top of it
+    #[arg(long)]
+    profile: Option<String>,
     /// Render image only
     #[arg(long, action = ArgAction::SetTrue)]
     no_bubble: bool,
+    /// Print only the bubble, with a small ASCII speaker instead of an image
+    #[arg(long, action = ArgAction::SetTrue, conflicts_with_all = ["no_bubble", "image", "count"])]
+    no_image: bool,
+    /// Eyes of the ASCII speaker shown with --no-image (two characters)
+    #[arg(long)]
+    eyes: Option<String>,
+    /// Tongue of the ASCII speaker shown with --no-image (two characters)
+    #[arg(long)]
+    tongue: Option<String>,
     /// Deterministic selection
     #[arg(long)]
     seed: Option<u64>,
+    /// Show the same image and message all day (seeded from the local date)
+    #[arg(long, action = ArgAction::SetTrue)]
+    daily: bool,
     /// Force chafa format
     #[arg(long)]
     format: Option<ChafaFormat>,
@@ -57,69 +163,534 @@ struct Cli {
     /// Maximum image height ratio (0.0-1.0)
     #[arg(long)]
     max_height_ratio: Option<f32>,
+    /// How the image fills its box: keep aspect (fit), ignore it (stretch) or upscale (fill)
+    #[arg(long)]
+    sizing: Option<Sizing>,
+    /// Dithering mode passed to chafa
+    #[arg(long)]
+    dither: Option<Dither>,
+    /// Dither grain size in pixels, "W" or "WxH" with 1, 2, 4 or 8
+    #[arg(long, value_parser = parse_dither_grain)]
+    dither_grain: Option<String>,
+    /// Symbol set chafa draws with in the symbols format (e.g. block, braille)
+    #[arg(long)]
+    symbols: Option<SymbolSet>,
+    /// Background color for transparent areas as hex (e.g. "#282828"), overriding the theme
+    #[arg(long, value_parser = parse_hex_color)]
+    bg: Option<String>,
+    /// Name shown under the bubble, like "-- Tux"
+    #[arg(long)]
+    speaker: Option<String>,
+    /// Color the bubble text with a rainbow gradient
+    #[arg(long, action = ArgAction::SetTrue)]
+    rainbow: bool,
+    /// Phase offset for --rainbow (random by default, or derived from --seed)
+    #[arg(long)]
+    rainbow_seed: Option<u64>,
+    /// Use only 7-bit ASCII in the bubble and ASCII symbols for the image
+    #[arg(long, action = ArgAction::SetTrue)]
+    ascii: bool,
+    /// Maximum bubble text width in columns (0 = terminal width)
+    #[arg(long)]
+    bubble_width: Option<usize>,
+    /// Bubble position relative to the image
+    #[arg(long, value_enum, default_value_t = BubblePosition::Top)]
+    position: BubblePosition,
+    /// Show this frame (0-based) of a GIF when not animating
+    #[arg(long)]
+    frame: Option<usize>,
+    /// Draw the bubble over the top rows of the image instead of above it
+    #[arg(long, action = ArgAction::SetTrue, conflicts_with = "position")]
+    overlay: bool,
+    /// Center the image horizontally in the terminal
+    #[arg(long, action = ArgAction::SetTrue)]
+    center_image: bool,
+    /// Center the bubble and image vertically in the terminal
+    #[arg(long, action = ArgAction::SetTrue)]
+    vcenter: bool,
+    /// Horizontal bubble alignment relative to the image
+    #[arg(long)]
+    bubble_align: Option<BubbleAlign>,
+    /// Exact image width in columns (overrides terminal detection)
+    #[arg(long)]
+    width: Option<usize>,
+    /// Exact image height in rows (overrides max_height_ratio)
+    #[arg(long)]
+    height: Option<usize>,
     /// Enable animation
     #[arg(long, action = ArgAction::SetTrue)]
     animate: bool,
+    /// Animation frame rate (with --animate)
+    #[arg(long)]
+    fps: Option<u32>,
+    /// Number of times to play the animation (with --animate)
+    #[arg(long)]
+    loops: Option<u32>,
+    /// Print the image file name between the bubble and pack images
+    #[arg(long, action = ArgAction::SetTrue)]
+    caption: bool,
+    /// Print a pack/license credit line after pack images
+    #[arg(long, action = ArgAction::SetTrue)]
+    credit: bool,
+    /// Output format: plain terminal text or an HTML snippet
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    format_out: OutputFormat,
+    /// Write the output to this file instead of stdout
+    #[arg(long)]
+    output: Option<PathBuf>,
+    /// Render the image even when stdout is not a terminal
+    #[arg(long, action = ArgAction::SetTrue)]
+    force_render: bool,
+    /// Print cache and timing statistics to stderr after rendering
+    #[arg(long, action = ArgAction::SetTrue)]
+    stats: bool,
+    /// Kill chafa after this many milliseconds
+    #[arg(long)]
+    timeout: Option<u64>,
+    /// Extra argument passed verbatim to chafa (repeatable)
+    #[arg(long = "chafa-arg", allow_hyphen_values = true)]
+    chafa_args: Vec<String>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// Pre-render pack images into the cache at the current terminal size
+    WarmCache {
+        /// Only warm this pack (defaults to all packs)
+        #[arg(long)]
+        pack: Option<String>,
+    },
+    /// Time every format and color combination on one image
+    Bench {
+        /// Image to render (defaults to the first image of the default pack)
+        #[arg(long)]
+        image: Option<PathBuf>,
+    },
+    /// Print a shell completion script to stdout
+    Completions {
+        /// Shell to generate completions for
+        shell: Shell,
+    },
+    /// Check a pack directory for problems before publishing
+    Validate {
+        /// Pack directory containing pack.toml
+        dir: PathBuf,
+    },
+    /// Create a pack directory with a pack.toml, images/ and messages.txt
+    NewPack {
+        /// Directory to create
+        dir: PathBuf,
+        /// Pack name (defaults to the directory name)
+        #[arg(long)]
+        name: Option<String>,
+        /// Pack version
+        #[arg(long, default_value = "0.1.0")]
+        version: String,
+        /// License of the images
+        #[arg(long, default_value = "CC-BY-4.0")]
+        license: String,
+        /// One-line description
+        #[arg(long, default_value = "")]
+        description: String,
+        /// Write into a directory that is not empty
+        #[arg(long, action = ArgAction::SetTrue)]
+        force: bool,
+    },
 }
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(default)]
 struct Config {
     enabled: bool,
+    quiet: bool,
     default_pack: String,
+    default_message: String,
+    time_greeting: bool,
     format: ChafaFormat,
+    format_fallbacks: Vec<ChafaFormat>,
     colors: ChafaColors,
+    #[serde(serialize_with = "serialize_short_f32")]
     max_height_ratio: f32,
+    sizing: Sizing,
+    dither: Option<Dither>,
+    dither_grain: Option<String>,
+    symbols: Option<SymbolSet>,
     bubble_style: String,
+    bubble_max_width: usize,
+    bubble_padding: usize,
+    bubble_align: BubbleAlign,
+    max_bubble_lines: usize,
+    tall_bubble: TallBubble,
+    tail_position: TailPosition,
+    direction: Direction,
+    long_word: LongWord,
+    max_message_chars: usize,
+    eyes: String,
+    tongue: String,
+    theme: Theme,
+    bg: Option<String>,
+    bubble: BubbleConfig,
+    ascii_borders: bool,
     cache: bool,
     animate: bool,
+    daily: bool,
     cache_max_mb: u64,
+    cache_max_entries: usize,
+    cache_dir: Option<PathBuf>,
+    fps: u32,
+    loops: u32,
+    chafa_timeout_ms: u64,
+    chafa_extra_args: Vec<String>,
+    extra_image_exts: Vec<String>,
+    image_blocklist: Vec<String>,
+    follow_symlinks: bool,
+    dedup_images: bool,
+    fallback_image: Option<PathBuf>,
+    reset_after: bool,
+    show_attribution: bool,
+    pack_selection: PackSelection,
+    history_size: usize,
+    /// Config files merged into this one, system first
+    #[serde(skip)]
+    files: Vec<PathBuf>,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
             enabled: true,
+            quiet: false,
             default_pack: "default".to_string(),
+            default_message: DEFAULT_MESSAGE.to_string(),
+            time_greeting: false,
             format: ChafaFormat::Auto,
+            format_fallbacks: Vec::new(),
             colors: ChafaColors::Auto,
             max_height_ratio: DEFAULT_MAX_HEIGHT_RATIO,
+            sizing: Sizing::Fit,
+            dither: None,
+            dither_grain: None,
+            symbols: None,
             bubble_style: "classic".to_string(),
+            bubble_max_width: DEFAULT_BUBBLE_MAX_WIDTH,
+            bubble_padding: DEFAULT_BUBBLE_PADDING,
+            bubble_align: BubbleAlign::Left,
+            max_bubble_lines: 0,
+            tall_bubble: TallBubble::Squash,
+            tail_position: TailPosition::Right,
+            direction: Direction::Auto,
+            long_word: LongWord::Break,
+            max_message_chars: 0,
+            eyes: "oo".to_string(),
+            tongue: "  ".to_string(),
+            theme: Theme::Auto,
+            bg: None,
+            bubble: BubbleConfig::default(),
+            ascii_borders: false,
             cache: true,
             animate: false,
+            daily: false,
             cache_max_mb: DEFAULT_CACHE_MAX_MB,
+            cache_max_entries: DEFAULT_CACHE_MAX_ENTRIES,
+            cache_dir: None,
+            fps: 0,
+            loops: 1,
+            chafa_timeout_ms: DEFAULT_CHAFA_TIMEOUT_MS,
+            chafa_extra_args: Vec::new(),
+            extra_image_exts: Vec::new(),
+            image_blocklist: Vec::new(),
+            follow_symlinks: true,
+            dedup_images: false,
+            fallback_image: None,
+            reset_after: true,
+            show_attribution: false,
+            pack_selection: PackSelection::Uniform,
+            history_size: DEFAULT_HISTORY_SIZE,
+            files: Vec::new(),
         }
     }
 }
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+struct BubbleConfig {
+    chars: BubbleChars,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+struct BubbleChars {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bottom: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    left: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    right: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_left: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_right: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bottom_left: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bottom_right: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    single_left: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    single_right: Option<String>,
+}
+
+impl BubbleChars {
+    fn glyphs(&self) -> Result<BubbleGlyphs> {
+        let pick = |name: &str, value: &Option<String>, classic: char| -> Result<char> {
+            let Some(value) = value else {
+                return Ok(classic);
+            };
+            let mut chars = value.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) if UnicodeWidthChar::width(c) == Some(1) => Ok(c),
+                _ => Err(anyhow!(
+                    "bubble.chars.{name} must be a single character of display width 1, got {value:?}"
+                )),
+            }
+        };
+        let classic = CLASSIC_GLYPHS;
+        Ok(BubbleGlyphs {
+            top: pick("top", &self.top, classic.top)?,
+            bottom: pick("bottom", &self.bottom, classic.bottom)?,
+            left: pick("left", &self.left, classic.left)?,
+            right: pick("right", &self.right, classic.right)?,
+            top_left: pick("top_left", &self.top_left, classic.top_left)?,
+            top_right: pick("top_right", &self.top_right, classic.top_right)?,
+            bottom_left: pick("bottom_left", &self.bottom_left, classic.bottom_left)?,
+            bottom_right: pick("bottom_right", &self.bottom_right, classic.bottom_right)?,
+            single_left: pick("single_left", &self.single_left, classic.single_left)?,
+            single_right: pick("single_right", &self.single_right, classic.single_right)?,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct BubbleGlyphs {
+    top: char,
+    bottom: char,
+    left: char,
+    right: char,
+    top_left: char,
+    top_right: char,
+    bottom_left: char,
+    bottom_right: char,
+    single_left: char,
+    single_right: char,
+}
+
+const CLASSIC_GLYPHS: BubbleGlyphs = BubbleGlyphs {
+    top: '_',
+    bottom: '-',
+    left: '|',
+    right: '|',
+    top_left: '/',
+    top_right: '\\',
+    bottom_left: '\\',
+    bottom_right: '/',
+    single_left: '<',
+    single_right: '>',
+};
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
 struct PackMeta {
     name: String,
     version: String,
     license: String,
     description: String,
     images_dir: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    include: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    exclude: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    speaker: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    attribution_url: Option<String>,
+    #[serde(default = "default_true")]
+    enabled: bool,
+    /// Frame to show for GIFs when not animating, keyed by file name.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    frames: BTreeMap<String, usize>,
+    /// Used when neither the command line nor the config picks a format.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    format: Option<ChafaFormat>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    colors: Option<ChafaColors>,
+}
+
+// `--format` and a non-auto config value win over the pack's preference,
+// which wins over auto-detection.
+fn pack_format(format: ChafaFormat, meta: Option<&PackMeta>) -> ChafaFormat {
+    match (format, meta.and_then(|meta| meta.format)) {
+        (ChafaFormat::Auto, Some(pack_format)) => {
+            info!("format: {} (from the pack)", pack_format.as_arg());
+            pack_format
+        }
+        (format, _) => format,
+    }
+}
+
+fn pack_colors(colors: ChafaColors, meta: Option<&PackMeta>) -> ChafaColors {
+    match (colors, meta.and_then(|meta| meta.colors)) {
+        (ChafaColors::Auto, Some(pack_colors)) => pack_colors,
+        (colors, _) => colors,
+    }
 }
 
-#[derive(Clone, Debug)]
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
 struct Pack {
     meta: PackMeta,
+    root: PathBuf,
     images: Vec<PathBuf>,
-    messages: Vec<String>,
+    // Copies dropped by `dedup_images`, keyed by the image that was kept, so
+    // a blocklisted original can be replaced by one of its copies.
+    #[serde(default)]
+    duplicates: BTreeMap<PathBuf, Vec<PathBuf>>,
+    messages: BTreeMap<String, Vec<String>>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct EmptyPack {
+    name: String,
+    images_dir: PathBuf,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+struct PackScan {
+    packs: Vec<Pack>,
+    empty: Vec<EmptyPack>,
+    #[serde(default)]
+    shadowed: Vec<ShadowedPack>,
+    #[serde(default)]
+    reserved: Vec<ReservedPack>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct ShadowedPack {
+    name: String,
+    winner: PathBuf,
+    shadowed: PathBuf,
+}
+
+// A pack whose name `--pack` reads as a selector, so it cannot be picked by name.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct ReservedPack {
+    name: String,
+    root: PathBuf,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct PackIndex {
+    key: String,
+    stamps: Vec<(PathBuf, Option<u128>)>,
+    scan: PackScan,
+}
+
+#[derive(Debug, Serialize)]
+struct DoctorReport<'a> {
+    chafa: &'a Path,
+    chafa_version: Option<String>,
+    chafa_command: String,
+    detected_format: ChafaFormat,
+    detection_signals: Vec<String>,
+    terminal_cols: usize,
+    terminal_rows: usize,
+    no_color: bool,
+    disabled_by_env: bool,
+    stdout_tty: bool,
+    seed: Option<u64>,
+    seed_source: SeedSource,
+    config: &'a Config,
+    config_files: &'a [PathBuf],
+    config_dir: Option<PathBuf>,
+    data_dir: Option<PathBuf>,
+    cache_dir: PathBuf,
+    cache_dir_source: &'static str,
+    pack_search_paths: Vec<SearchPathReport>,
+    packs_found: usize,
+    skipped_packs: &'a [EmptyPack],
+    shadowed_packs: &'a [ShadowedPack],
+    reserved_packs: &'a [ReservedPack],
+}
+
+#[derive(Debug, Serialize)]
+struct SearchPathReport {
+    path: PathBuf,
+    exists: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct PackListEntry<'a> {
+    #[serde(flatten)]
+    meta: &'a PackMeta,
+    image_count: usize,
+    images: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    image_details: Vec<ImageDetails>,
+}
+
+#[derive(Debug, Serialize)]
+struct ImageDetails {
+    name: String,
+    width: Option<u32>,
+    height: Option<u32>,
+    bytes: Option<u64>,
+}
+
+fn image_details(image: &Path) -> ImageDetails {
+    let dimensions = image_dimensions(image);
+    ImageDetails {
+        name: image
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+        width: dimensions.map(|(width, _)| width),
+        height: dimensions.map(|(_, height)| height),
+        bytes: fs::metadata(image).ok().map(|meta| meta.len()),
+    }
+}
+
+fn format_size(bytes: u64) -> String {
+    match bytes {
+        0..1024 => format!("{bytes} B"),
+        1024..1_048_576 => format!("{:.1} KiB", bytes as f64 / 1024.0),
+        _ => format!("{:.1} MiB", bytes as f64 / 1_048_576.0),
+    }
+}
+
+// Reads the pixel size from the file header without decoding the image.
+fn image_dimensions(path: &Path) -> Option<(u32, u32)> {
+    let size = imagesize::size(path).ok()?;
+    Some((
+        u32::try_from(size.width).ok()?,
+        u32::try_from(size.height).ok()?,
+    ))
 }
 
-#[derive(Clone, Copy, Debug, Deserialize, ValueEnum, PartialEq)]
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, ValueEnum, PartialEq)]
 #[serde(rename_all = "lowercase")]
 enum ChafaFormat {
     Auto,
-    #[serde(alias = "symbols")]
+    #[serde(rename = "symbols", alias = "unicode")]
     #[value(alias = "symbols")]
     Unicode,
     Kitty,
-    #[serde(alias = "iterm")]
+    #[serde(rename = "iterm", alias = "iterm2")]
     #[value(alias = "iterm")]
     Iterm2,
-    #[serde(alias = "sixels")]
+    #[serde(rename = "sixels", alias = "sixel")]
     #[value(alias = "sixels")]
     Sixel,
 }
@@ -136,692 +707,6042 @@ impl ChafaFormat {
     }
 }
 
-#[derive(Clone, Copy, Debug, Deserialize, ValueEnum, PartialEq)]
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, ValueEnum, PartialEq)]
 #[serde(rename_all = "lowercase")]
 enum ChafaColors {
     Auto,
-    #[serde(alias = "full")]
+    #[serde(rename = "full", alias = "truecolor")]
     #[value(alias = "full")]
     Truecolor,
-    #[serde(alias = "256")]
+    #[serde(rename = "256", alias = "c256")]
     #[value(alias = "256")]
     C256,
-    #[serde(alias = "16")]
+    #[serde(rename = "16", alias = "c16")]
     #[value(alias = "16")]
     C16,
+    None,
 }
 
-impl ChafaColors {
-    fn as_arg(self) -> &'static str {
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, ValueEnum, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Sizing {
+    Fit,
+    Stretch,
+    Fill,
+}
+
+impl Sizing {
+    fn chafa_args(self) -> &'static [&'static str] {
         match self {
-            ChafaColors::Auto => "auto",
-            ChafaColors::Truecolor => "full",
-            ChafaColors::C256 => "256",
-            ChafaColors::C16 => "16",
+            Sizing::Fit => &[],
+            Sizing::Stretch => &["--stretch"],
+            Sizing::Fill => &["--scale", "max"],
         }
     }
 }
 
-fn main() -> Result<()> {
-    let cli = Cli::parse();
-    let config = load_config()?;
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, ValueEnum, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Dither {
+    None,
+    Ordered,
+    Diffusion,
+}
 
-    if !config.enabled {
-        return Ok(());
+impl Dither {
+    fn as_arg(self) -> &'static str {
+        match self {
+            Dither::None => "none",
+            Dither::Ordered => "ordered",
+            Dither::Diffusion => "diffusion",
+        }
     }
+}
 
-    let chafa = find_chafa().map_err(|e| {
-        eprintln!("{e}");
-        anyhow!("chafa missing")
-    })?;
-
-    let (term_cols, term_rows) = terminal_dimensions();
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, ValueEnum, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum SymbolSet {
+    All,
+    Ascii,
+    Block,
+    Border,
+    Braille,
+    Geometric,
+    Half,
+    Legacy,
+    Quad,
+    Sextant,
+    Solid,
+    Stipple,
+    Wedge,
+}
 
-    if cli.doctor {
-        print_doctor(&chafa, term_cols, term_rows, &config)?;
-        return Ok(());
+impl SymbolSet {
+    fn as_arg(self) -> &'static str {
+        match self {
+            SymbolSet::All => "all",
+            SymbolSet::Ascii => "ascii",
+            SymbolSet::Block => "block",
+            SymbolSet::Border => "border",
+            SymbolSet::Braille => "braille",
+            SymbolSet::Geometric => "geometric",
+            SymbolSet::Half => "half",
+            SymbolSet::Legacy => "legacy",
+            SymbolSet::Quad => "quad",
+            SymbolSet::Sextant => "sextant",
+            SymbolSet::Solid => "solid",
+            SymbolSet::Stipple => "stipple",
+            SymbolSet::Wedge => "wedge",
+        }
     }
+}
 
-    let packs = scan_packs()?;
-    if cli.list {
-        print_pack_list(&packs);
-        return Ok(());
+impl ChafaColors {
+    fn as_arg(self) -> &'static str {
+        match self {
+            ChafaColors::Auto => "auto",
+            ChafaColors::Truecolor => "full",
+            ChafaColors::C256 => "256",
+            ChafaColors::C16 => "16",
+            ChafaColors::None => "none",
+        }
     }
+}
 
-    let format = cli.format.unwrap_or(config.format);
-    let colors = cli.colors.unwrap_or(config.colors);
-    let max_height_ratio = cli.max_height_ratio.unwrap_or(config.max_height_ratio);
-    let animate = if cli.animate { true } else { config.animate };
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, ValueEnum, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum BubbleAlign {
+    Left,
+    Center,
+}
 
-    let message = resolve_message(&cli, &packs, &config, cli.seed)?;
-    let image_path = resolve_image(&cli, &packs, &config, cli.seed)?;
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum LongWord {
+    Break,
+    Truncate,
+}
 
-    let bubble = if cli.no_bubble {
-        Vec::new()
-    } else {
-        render_bubble(&message, term_cols)
-    };
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Direction {
+    Ltr,
+    Rtl,
+    Auto,
+}
 
-    if !bubble.is_empty() {
-        for line in &bubble {
-            println!("{line}");
+impl Direction {
+    // Like the Unicode bidi algorithm, the first strong character decides.
+    fn is_rtl(self, text: &str) -> bool {
+        match self {
+            Direction::Ltr => false,
+            Direction::Rtl => true,
+            Direction::Auto => text
+                .chars()
+                .find(|c| c.is_alphabetic())
+                .is_some_and(is_rtl_char),
         }
-    } else if !message.is_empty() && !cli.no_bubble {
-        println!("{message}");
     }
+}
 
-    let bubble_height = bubble.len();
-    let max_image_rows = ((term_rows as f32) * max_height_ratio).floor() as usize;
-    let remaining_rows = term_rows.saturating_sub(bubble_height + 1);
-    let image_rows = min(max_image_rows, remaining_rows).max(1);
+// Hebrew, Arabic, Syriac, Thaana, NKo and the other right-to-left scripts,
+// plus their presentation forms.
+fn is_rtl_char(c: char) -> bool {
+    matches!(
+        c,
+        '\u{0590}'..='\u{08FF}'
+            | '\u{FB1D}'..='\u{FDFF}'
+            | '\u{FE70}'..='\u{FEFF}'
+            | '\u{10800}'..='\u{10FFF}'
+            | '\u{1E800}'..='\u{1EFFF}'
+    )
+}
 
-    let image_output = render_image(
-        &chafa,
-        &image_path,
-        RenderOptions {
-            cols: term_cols,
-            rows: image_rows,
-            format,
-            colors,
-            animate,
-            cache_enabled: config.cache,
-            cache_max_mb: config.cache_max_mb,
-        },
-    )?;
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum TailPosition {
+    Left,
+    Center,
+    Right,
+    None,
+}
 
-    print!("{image_output}");
+#[derive(Clone, Copy, Debug, ValueEnum, PartialEq)]
+enum BubblePosition {
+    Top,
+    Bottom,
+}
 
-    Ok(())
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+enum TallBubble {
+    Squash,
+    DropImage,
 }
 
-fn terminal_dimensions() -> (usize, usize) {
-    if let Some((Width(w), Height(h))) = terminal_size() {
-        (w as usize, h as usize)
-    } else {
-        (80, 24)
-    }
+#[derive(Clone, Copy, Debug, ValueEnum, PartialEq)]
+enum OutputFormat {
+    Text,
+    Html,
 }
 
-fn load_config() -> Result<Config> {
-    let Some(proj_dirs) = ProjectDirs::from("", "", "leftysay") else {
-        return Ok(Config::default());
-    };
-    let config_path = proj_dirs.config_dir().join("config.toml");
-    if !config_path.exists() {
-        return Ok(Config::default());
-    }
-    let contents = fs::read_to_string(&config_path)
-        .with_context(|| format!("reading config {}", config_path.display()))?;
-    let mut config: Config = toml::from_str(&contents).context("parsing config")?;
-    if config.max_height_ratio <= 0.0 || config.max_height_ratio > 1.0 {
-        config.max_height_ratio = DEFAULT_MAX_HEIGHT_RATIO;
-    }
-    if config.cache_max_mb == 0 {
-        config.cache_max_mb = DEFAULT_CACHE_MAX_MB;
-    }
-    Ok(config)
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum PackSelection {
+    Uniform,
+    Weighted,
 }
 
-fn find_chafa() -> Result<PathBuf> {
-    if let Ok(path) = std::env::var("LEFTYSAY_CHAFA") {
-        return Ok(PathBuf::from(path));
-    }
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Theme {
+    Auto,
+    Dark,
+    Light,
+}
 
-    let candidate = if cfg!(windows) { "chafa.exe" } else { "chafa" };
-    if let Some(paths) = std::env::var_os("PATH") {
-        for dir in std::env::split_paths(&paths) {
-            let full = dir.join(candidate);
-            if full.is_file() {
-                return Ok(full);
-            }
+impl Theme {
+    /// Background chafa renders transparent pixels against.
+    fn bg_color(self) -> &'static str {
+        match self {
+            Theme::Light => "white",
+            Theme::Auto | Theme::Dark => "black",
         }
     }
+}
 
-    let install_hint = match std::env::consts::OS {
-        "linux" => "Install: sudo apt install chafa (Debian/Ubuntu) or sudo pacman -S chafa (Arch)",
-        "macos" => "Install: brew install chafa",
-        _ => "Install chafa from your package manager",
+fn init_logging(verbose: u8) {
+    let level = match verbose {
+        0 => log::LevelFilter::Off,
+        1 => log::LevelFilter::Info,
+        _ => log::LevelFilter::Debug,
     };
-    Err(anyhow!("leftysay requires chafa. {install_hint}"))
+    env_logger::Builder::new()
+        .filter_module("leftysay", level)
+        .format_timestamp(None)
+        .format_target(false)
+        .init();
 }
 
-fn pack_search_paths() -> Vec<PathBuf> {
-    let mut paths = Vec::new();
+fn main() -> Result<()> {
+    let cli = Cli::parse();
+    init_logging(cli.verbose);
+    match &cli.command {
+        Some(Commands::Validate { dir }) => {
+            // Validate with the same symlink and extension settings a scan uses.
+            let mut config = load_config(cli.config.as_deref(), selected_profile(&cli).as_deref())?;
+            apply_cli_overrides(&mut config, &cli);
+            return run_validate(dir, &config);
+        }
+        Some(Commands::NewPack {
+            dir,
+            name,
+            version,
+            license,
+            description,
+            force,
+        }) => {
+            let name = match name {
+                Some(name) => name.clone(),
+                None => dir
+                    .file_name()
+                    .map(|name| name.to_string_lossy().into_owned())
+                    .ok_or_else(|| anyhow!("cannot name a pack after {}", dir.display()))?,
+            };
+            let meta = PackMeta {
+                name,
+                version: version.clone(),
+                license: license.clone(),
+                description: description.clone(),
+                images_dir: "images".to_string(),
+                include: Vec::new(),
+                exclude: Vec::new(),
+                speaker: None,
+                attribution_url: None,
+                enabled: true,
+                frames: BTreeMap::new(),
+                format: None,
+                colors: None,
+            };
+            create_pack(dir, &meta, *force)?;
+            println!("created pack {} in {}", meta.name, dir.display());
+            println!("add images to {}", dir.join(&meta.images_dir).display());
+            return Ok(());
+        }
+        Some(Commands::Completions { shell }) => {
+            let mut command = Cli::command();
+            clap_complete::generate(*shell, &mut command, "leftysay", &mut std::io::stdout());
+            return Ok(());
+        }
+        _ => {}
+    }
 
-    if let Ok(extra) = std::env::var("LEFTYSAY_PACKS_DIR") {
-        paths.push(PathBuf::from(extra));
+    if disabled_by_env() && !cli.doctor && !cli.dump_config {
+        return Ok(());
     }
 
-    if let Some(proj_dirs) = ProjectDirs::from("", "", "leftysay") {
-        paths.push(proj_dirs.data_dir().join("packs"));
+    let profile = selected_profile(&cli);
+    let mut config = load_config(cli.config.as_deref(), profile.as_deref())?;
+    apply_cli_overrides(&mut config, &cli);
+
+    if cli.dump_config {
+        let mut out = open_output(cli.output.as_deref())?;
+        dump_config(&mut out, &config)?;
+        out.flush()?;
+        return Ok(());
     }
 
-    if cfg!(target_os = "macos") {
-        let brew_prefixes = [
-            std::env::var("HOMEBREW_PREFIX").ok(),
-            Some("/opt/homebrew".to_string()),
-            Some("/usr/local".to_string()),
-        ];
-        for prefix in brew_prefixes.iter().flatten() {
-            let candidate = Path::new(prefix).join("share/leftysay/packs");
-            if candidate.exists() {
-                paths.push(candidate);
-            }
-        }
-    } else if cfg!(target_os = "linux") {
-        paths.push(PathBuf::from("/usr/share/leftysay/packs"));
+    if let Some(Commands::WarmCache { pack }) = &cli.command {
+        let chafa = find_chafa()?;
+        return warm_cache(&chafa, &config, pack.as_deref());
+    }
+    if let Some(Commands::Bench { image }) = &cli.command {
+        let chafa = find_chafa()?;
+        let mut out = open_output(cli.output.as_deref())?;
+        run_bench(&mut out, &chafa, &config, image.as_deref())?;
+        out.flush()?;
+        return Ok(());
     }
 
-    if Path::new("packs").exists() {
-        paths.push(PathBuf::from("packs"));
+    if !config.enabled {
+        return Ok(());
     }
 
-    paths
-}
+    let quiet = config.quiet;
+    // The text-only cow never runs chafa, so it works without it.
+    let chafa = if cli.no_image && !cli.doctor {
+        None
+    } else {
+        match find_chafa() {
+            Ok(chafa) => Some(chafa),
+            Err(_) if quiet => return Ok(()),
+            Err(e) => {
+                eprintln!("{e}");
+                return Err(anyhow!("chafa missing"));
+            }
+        }
+    };
 
-fn scan_packs() -> Result<Vec<Pack>> {
-    let mut packs = Vec::new();
-    let mut seen = std::collections::HashSet::new();
+    let (term_cols, term_rows) = terminal_dimensions();
 
-    for base in pack_search_paths() {
-        if !base.exists() {
-            continue;
+    let (seed, seed_source) = resolve_seed(cli.seed, config.daily);
+    match seed {
+        Some(seed) => info!("seed: {seed} (from {})", seed_source.describe()),
+        None => info!("seed: none, picking at random"),
+    }
+    let mut out = open_output(cli.output.as_deref())?;
+    // A dry run neither reads nor writes the pack index.
+    let use_pack_index = config.cache && !cli.no_pack_cache && !cli.dry_run;
+    if let (true, Some(chafa)) = (cli.doctor, &chafa) {
+        let scan = load_packs(&config, use_pack_index, true)?;
+        let seed = (seed, seed_source);
+        if cli.json {
+            print_doctor_json(&mut out, chafa, term_cols, term_rows, &config, &scan, seed)?;
+        } else {
+            print_doctor(&mut out, chafa, term_cols, term_rows, &config, &scan, seed)?;
         }
+        out.flush()?;
+        return Ok(());
+    }
 
-        for entry in WalkDir::new(&base)
-            .max_depth(3)
-            .into_iter()
-            .filter_map(Result::ok)
-        {
-            if entry.file_name() == "pack.toml" {
-                let pack_root = entry.path().parent().unwrap_or(entry.path()).to_path_buf();
-                let meta = read_pack_meta(entry.path())?;
-                if seen.contains(&meta.name) {
-                    continue;
-                }
-                let images = collect_images(&pack_root, &meta.images_dir);
-                if images.is_empty() {
-                    continue;
-                }
-                let messages = read_messages(&pack_root);
-                packs.push(Pack {
-                    meta,
-                    images,
-                    messages,
-                });
-                seen.insert(packs.last().unwrap().meta.name.clone());
-            }
+    let scan = load_packs(&config, use_pack_index, cli.list)?;
+    let packs = scan.packs;
+    if cli.list {
+        for empty in &scan.empty {
+            eprintln!("warning: {}", describe_empty_pack(empty));
+        }
+        for shadowed in &scan.shadowed {
+            eprintln!("warning: {}", describe_shadowed_pack(shadowed));
         }
+        for reserved in &scan.reserved {
+            eprintln!("warning: {}", describe_reserved_pack(reserved));
+        }
+        if cli.json {
+            print_pack_list_json(&mut out, &packs, cli.details)?;
+        } else {
+            print_pack_list(&mut out, &packs, cli.details)?;
+        }
+        out.flush()?;
+        return Ok(());
     }
 
-    Ok(packs)
-}
-
-fn read_pack_meta(path: &Path) -> Result<PackMeta> {
-    let contents = fs::read_to_string(path)
-        .with_context(|| format!("reading pack meta {}", path.display()))?;
-    let meta: PackMeta = toml::from_str(&contents)
-        .with_context(|| format!("parsing pack meta {}", path.display()))?;
-    Ok(meta)
-}
+    let pack_name = config.default_pack.clone();
+    info!(
+        "pack: {pack_name:?} (from {})",
+        if cli.pack.is_some() {
+            "--pack"
+        } else {
+            "default_pack"
+        }
+    );
+    let blocklist = build_globset(&config.image_blocklist).context("parsing image_blocklist")?;
+    let owned_pack;
+    let pack = if pack_name == ALL_PACKS {
+        owned_pack = pool_packs(&packs, blocklist.as_ref());
+        Some(&owned_pack)
+    } else if let Some(dir) = pack_name.strip_prefix(DIR_PACK_PREFIX) {
+        owned_pack = implicit_pack(Path::new(dir), &config)?
+            .ok_or_else(|| anyhow!("no images found in {dir}"))?;
+        Some(&owned_pack)
+    } else {
+        resolve_pack(
+            &pack_name,
+            &packs,
+            config.pack_selection,
+            seed,
+            blocklist.as_ref(),
+        )?
+    };
 
-fn collect_images(pack_root: &Path, images_dir: &str) -> Vec<PathBuf> {
-    let dir = pack_root.join(images_dir);
-    if !dir.exists() {
-        return Vec::new();
+    let default_message = if config.time_greeting {
+        greeting_for(&system_locale(), chrono::Local::now().hour()).to_string()
+    } else {
+        config.default_message.clone()
+    };
+    let message = truncate_message(
+        resolve_message(&cli, pack, &default_message, seed)?,
+        config.max_message_chars,
+    );
+    // A seed must reproduce the same image, so history only applies to unseeded runs.
+    let use_history = seed.is_none() && config.history_size > 0;
+    let recent = if use_history {
+        read_history()
+    } else {
+        Vec::new()
+    };
+    let count = cli.count.unwrap_or(1);
+    let mut montage = Vec::new();
+    if count > 1 {
+        let pack = pack.ok_or_else(|| anyhow!("pack not found: {pack_name}"))?;
+        let images = unblocked_images(pack, blocklist.as_ref());
+        montage = pick_images(&images, count, &recent, seed)?;
     }
-    WalkDir::new(dir)
-        .into_iter()
-        .filter_map(Result::ok)
-        .filter(|entry| entry.file_type().is_file())
-        .filter(|entry| is_supported_image(entry.path()))
-        .map(|entry| entry.into_path())
-        .collect()
-}
+    let pack_of = |path: &PathBuf| match cli.image {
+        Some(_) => None,
+        None if pack_name == ALL_PACKS => packs.iter().find(|p| p.images.contains(path)),
+        None => pack,
+    };
+    let (image_path, image_pack) = match montage.first() {
+        Some(first) => {
+            if use_history && !cli.dry_run {
+                let _ = record_history(recent, &montage, config.history_size);
+            }
+            (first.clone(), pack_of(first))
+        }
+        None => match resolve_image(&cli, pack, &pack_name, seed, &recent, blocklist.as_ref()) {
+            Ok(path) => {
+                if use_history && !cli.dry_run && cli.image.is_none() && cli.image_name.is_none() {
+                    // History is best-effort; a read-only data dir should not break the greeting.
+                    let _ =
+                        record_history(recent, std::slice::from_ref(&path), config.history_size);
+                }
+                let image_pack = pack_of(&path);
+                (path, image_pack)
+            }
+            Err(e) => match &config.fallback_image {
+                Some(fallback) if cli.image_name.is_none() && fallback.is_file() => {
+                    (fallback.clone(), None)
+                }
+                _ => return Err(e),
+            },
+        },
+    };
+    let image_path = match remote_image_url(&image_path) {
+        // A dry run shows where the download would be kept without fetching it.
+        Some(url) if cli.dry_run => remote_image_paths(url, &cache_dir(&config)).0,
+        Some(url) => match fetch_remote_image(url, &cache_dir(&config)) {
+            Ok(path) => path,
+            Err(e) => match &config.fallback_image {
+                Some(fallback) if fallback.is_file() => fallback.clone(),
+                _ => return Err(e),
+            },
+        },
+        None => image_path,
+    };
 
-fn is_supported_image(path: &Path) -> bool {
-    let Some(ext) = path.extension().and_then(OsStr::to_str) else {
-        return false;
+    let ascii = config.ascii_borders;
+    let html = cli.format_out == OutputFormat::Html;
+    // The pack of the chosen image, so `--pack all` still honors it. A flag
+    // wins over the pack even when it asks for auto.
+    let pack_meta = image_pack.map(|pack| &pack.meta);
+    // chafa has no HTML mode, so HTML output converts its symbol output.
+    let format = if ascii || html {
+        ChafaFormat::Unicode
+    } else {
+        match cli.format {
+            Some(format) => format,
+            None => pack_format(config.format, pack_meta),
+        }
     };
-    matches!(ext.to_lowercase().as_str(), "png" | "jpg" | "jpeg" | "gif")
-}
+    if format == ChafaFormat::Auto {
+        let detection = detect_protocol(|name| std::env::var(name).ok());
+        info!(
+            "format: auto resolved to {} ({})",
+            detection.format.as_arg(),
+            if detection.signals.is_empty() {
+                "no terminal hints".to_string()
+            } else {
+                detection.signals.join(", ")
+            }
+        );
+    }
+    let colors = if no_color() {
+        ChafaColors::None
+    } else {
+        match cli.colors {
+            Some(colors) => colors,
+            None => pack_colors(config.colors, pack_meta),
+        }
+    };
+    let max_height_ratio = config.max_height_ratio;
+    let animate = config.animate;
+    let cache_enabled = config.cache;
+    let chafa_timeout_ms = config.chafa_timeout_ms;
+    let chafa_extra_args = config.chafa_extra_args.clone();
+    let bg = background_color(&config);
+    let symbols = if ascii {
+        Some(SymbolSet::Ascii)
+    } else {
+        config.symbols
+    };
+    // A symbol set from the config is simply unused by graphics protocols, but
+    // asking for one on the command line should not be silently dropped.
+    if cli.symbols.is_some() && !ascii {
+        let resolved = effective_format(format);
+        if resolved != ChafaFormat::Unicode {
+            return Err(anyhow!(
+                "--symbols only applies to the symbols format, but the image would be rendered as {}",
+                resolved.as_arg()
+            ));
+        }
+    }
 
-fn read_messages(pack_root: &Path) -> Vec<String> {
-    let path = pack_root.join("messages.txt");
-    if !path.exists() {
-        return Vec::new();
+    let animated = animate && is_animated_image(&image_path);
+    if !animated && (cli.fps.is_some() || cli.loops.is_some()) {
+        eprintln!("note: --fps and --loops only apply to animated images with --animate; ignoring");
     }
-    let contents = match fs::read_to_string(path) {
-        Ok(v) => v,
-        Err(_) => return Vec::new(),
+    let (fps, loops) = if animated {
+        (config.fps, config.loops.max(1))
+    } else {
+        (0, 1)
     };
-    contents
-        .lines()
-        .map(|line| line.trim())
-        .filter(|line| !line.is_empty())
-        .map(|line| line.to_string())
-        .collect()
-}
+    let frame = if animated || !is_gif(&image_path) {
+        0
+    } else {
+        cli.frame
+            .or_else(|| {
+                let name = image_path.file_name()?.to_str()?;
+                image_pack?.meta.frames.get(name).copied()
+            })
+            .unwrap_or(0)
+    };
+    if cli.frame.is_some() && image_path == Path::new("-") {
+        return Err(anyhow!(
+            "--frame needs a GIF file; it cannot be used with --image -"
+        ));
+    }
+    if frame > 0 && !cli.no_image {
+        let frames = gif_frame_delays(&image_path)
+            .with_context(|| format!("reading {}", image_path.display()))?
+            .len();
+        if frame >= frames {
+            return Err(anyhow!(
+                "frame {frame} does not exist: {} has {frames} frame(s), counting from 0",
+                image_path.display()
+            ));
+        }
+    }
 
-fn resolve_message(
-    cli: &Cli,
-    packs: &[Pack],
-    config: &Config,
-    seed: Option<u64>,
-) -> Result<String> {
-    if let Some(text) = &cli.text {
-        return Ok(text.clone());
+    // Escape sequences are useless in pipes, so only the text is printed there.
+    let escapes_allowed =
+        cli.force_render || html || cli.output.is_some() || std::io::stdout().is_terminal();
+    let show_image = !cli.no_image && escapes_allowed;
+
+    let mut bubble = if cli.no_bubble {
+        Vec::new()
+    } else {
+        let speaker = cli
+            .speaker
+            .as_deref()
+            .or_else(|| image_pack.or(pack).and_then(|p| p.meta.speaker.as_deref()));
+        let bubble_options = BubbleOptions {
+            max_width: config.bubble_max_width,
+            padding: config.bubble_padding,
+            speaker,
+            glyphs: config.bubble.chars.glyphs()?,
+            rainbow: rainbow_phase(&cli, escapes_allowed, colors, seed),
+            ascii,
+            max_lines: config.max_bubble_lines,
+            tail_up: cli.position == BubblePosition::Bottom,
+            tail: config.tail_position,
+            rtl: config.direction.is_rtl(&message),
+            long_word: config.long_word,
+        };
+        let mut bubble = render_bubble(&message, term_cols, &bubble_options);
+        if cli.no_image && !bubble_options.tail_up && bubble.len() > 1 {
+            let (eyes, tongue) = if ascii {
+                (to_ascii(&config.eyes), to_ascii(&config.tongue))
+            } else {
+                (config.eyes.clone(), config.tongue.clone())
+            };
+            // Continue the tail's diagonal into the speaker's head.
+            let head_col = match config.tail_position {
+                TailPosition::None => 1,
+                _ => bubble.last().map_or(0, |tail| visible_width(tail)),
+            };
+            bubble.extend(render_speaker(head_col, &eyes, &tongue));
+        }
+        bubble
+    };
+
+    let image_cols = cli.width.unwrap_or(term_cols).max(1);
+    // Only text can be drawn over, and a bubble wider than the image would
+    // hide all of it; in those cases the bubble is stacked as usual. This
+    // checks the columns given to chafa; the rendered width is checked below.
+    let bubble_width = bubble.iter().map(|line| visible_width(line)).max();
+    let overlay = cli.overlay
+        && show_image
+        && bubble_width.is_some_and(|width| width <= image_cols)
+        && (!montage.is_empty() || effective_format(format) == ChafaFormat::Unicode);
+    if cli.overlay && !overlay {
+        info!("overlay: not possible here, stacking the bubble instead");
     }
+    let image_rows = cli
+        .height
+        .unwrap_or_else(|| {
+            let bubble_rows = if overlay { 0 } else { bubble.len() };
+            image_row_budget(term_rows, max_height_ratio, bubble_rows)
+        })
+        .max(1);
 
-    if let Some(text) = read_stdin_text()? {
-        return Ok(text);
+    // A bubble that fills the terminal would leave a one-row image.
+    let drop_image = config.tall_bubble == TallBubble::DropImage
+        && cli.height.is_none()
+        && bubble.len() + 1 >= term_rows;
+    if drop_image && show_image {
+        eprintln!("note: the message is taller than the terminal; skipping the image");
     }
 
-    let pack_name = cli
-        .pack
-        .clone()
-        .unwrap_or_else(|| config.default_pack.clone());
-    if let Some(pack) = packs.iter().find(|p| p.meta.name == pack_name) {
-        if !pack.messages.is_empty() {
-            let idx = pick_index(pack.messages.len(), seed)?;
-            return Ok(pack.messages[idx].clone());
+    let cache_root = cache_dir(&config);
+    let renderer = chafa.as_deref().map(|chafa| {
+        let options = RenderOptions {
+            cols: image_cols,
+            rows: image_rows,
+            format,
+            colors,
+            animate,
+            fps,
+            duration_ms: loops_duration_ms(&image_path, fps, loops),
+            cache_enabled,
+            cache_max_mb: config.cache_max_mb,
+            cache_max_entries: config.cache_max_entries,
+            cache_dir: &cache_root,
+            frame,
+            timeout: Duration::from_millis(chafa_timeout_ms),
+            extra_args: &chafa_extra_args,
+            chafa_id: chafa_identity(chafa),
+            sizing: config.sizing,
+            dither: config.dither,
+            dither_grain: config.dither_grain.as_deref(),
+            symbols,
+            bg: bg.as_deref(),
+            spinner: !config.quiet
+                && std::io::stdout().is_terminal()
+                && std::io::stderr().is_terminal(),
+            // HTML and ASCII output only work with symbols.
+            format_fallbacks: if ascii || html {
+                &[]
+            } else {
+                &config.format_fallbacks
+            },
+        };
+        (chafa, options)
+    });
+    if cli.dry_run {
+        let Some((chafa, options)) = renderer else {
+            return Ok(());
+        };
+        if montage.is_empty() {
+            // chafa would be given the extracted frame.
+            let path = if options.frame > 0 {
+                gif_frame_path(&image_path, options.frame, options.cache_dir)
+            } else {
+                image_path.clone()
+            };
+            writeln!(
+                out,
+                "{}",
+                shell_command(chafa, &chafa_args(&path, &options))
+            )?;
+        } else {
+            let tile = montage_tile_options(options, montage.len());
+            for image in &montage {
+                writeln!(out, "{}", shell_command(chafa, &chafa_args(image, &tile)))?;
+            }
         }
+        out.flush()?;
+        return Ok(());
     }
 
-    Ok(DEFAULT_MESSAGE.to_string())
-}
+    let write_bubble = |text: &mut Vec<u8>, bubble: &[String]| -> std::io::Result<()> {
+        if !bubble.is_empty() {
+            for line in bubble {
+                writeln!(text, "{line}")?;
+            }
+        } else if !message.is_empty() && !cli.no_bubble {
+            writeln!(text, "{message}")?;
+        }
+        Ok(())
+    };
+    // When nothing about the bubble depends on the rendered image, it is
+    // shown on the terminal while chafa is still running. It has to fit on
+    // screen so it can be erased again if the render fails.
+    let bubble_first = show_image
+        && !drop_image
+        && !html
+        && !overlay
+        && !cli.vcenter
+        && cli.position == BubblePosition::Top
+        && config.bubble_align != BubbleAlign::Center
+        && cli.output.is_none()
+        && std::io::stdout().is_terminal()
+        && bubble.len() < term_rows;
 
-fn read_stdin_text() -> Result<Option<String>> {
-    if std::io::stdin().is_terminal() {
-        return Ok(None);
-    }
-    let mut buffer = String::new();
-    std::io::stdin().read_to_string(&mut buffer)?;
-    let trimmed = buffer.trim();
-    if trimmed.is_empty() {
-        Ok(None)
+    let rendered = if let Some((chafa, options)) = renderer.filter(|_| show_image && !drop_image) {
+        let render = || {
+            if montage.is_empty() {
+                image_source(&image_path).and_then(|source| render_image(chafa, &source, options))
+            } else {
+                render_montage(chafa, &montage, options)
+            }
+        };
+        let rendered = if bubble_first {
+            let mut text = Vec::new();
+            write_bubble(&mut text, &bubble)?;
+            render_behind_bubble(&mut out, &text, render)?
+        } else {
+            render()
+        };
+        match rendered {
+            Ok(rendered) => Some(rendered),
+            Err(_) if quiet => return Ok(()),
+            Err(e) => return Err(e),
+        }
     } else {
-        Ok(Some(trimmed.to_string()))
+        None
+    };
+    let mut image_output = rendered.as_ref().map_or("", |r| r.text.as_str());
+
+    // Graphics protocols are a single escape sequence, so only text can be
+    // padded line by line.
+    let indent = if cli.center_image
+        && (!montage.is_empty() || effective_format(format) == ChafaFormat::Unicode)
+    {
+        image_indent(image_output, term_cols)
+    } else {
+        0
+    };
+    if rendered.is_some() && config.bubble_align == BubbleAlign::Center {
+        center_bubble(&mut bubble, image_output, indent, term_cols);
     }
-}
 
-fn resolve_image(cli: &Cli, packs: &[Pack], config: &Config, seed: Option<u64>) -> Result<PathBuf> {
-    if let Some(path) = &cli.image {
-        return Ok(path.clone());
+    let overlaid;
+    // chafa keeps the aspect ratio, so the image can be much narrower than
+    // the columns it was given.
+    let overlay = overlay && overlay_fits(&bubble, image_output);
+    if cli.overlay && !overlay {
+        info!("overlay: the bubble is wider than the image, stacking it instead");
+    }
+    if overlay {
+        overlaid = overlay_text(&bubble, image_output);
+        image_output = &overlaid;
+    }
+    let centered;
+    if indent > 0 {
+        centered = indent_lines(image_output, indent);
+        image_output = &centered;
     }
-    let pack_name = cli
-        .pack
-        .clone()
-        .unwrap_or_else(|| config.default_pack.clone());
-    let pack = packs
-        .iter()
-        .find(|p| p.meta.name == pack_name)
-        .ok_or_else(|| anyhow!("pack not found: {pack_name}"))?;
-    let idx = pick_index(pack.images.len(), seed)?;
-    Ok(pack.images[idx].clone())
-}
 
-fn pick_index(len: usize, seed: Option<u64>) -> Result<usize> {
-    if len == 0 {
-        return Err(anyhow!("no images available"));
+    let mut bubble_text = Vec::new();
+    // An overlaid bubble is already part of the image.
+    if !overlay && !bubble_first {
+        write_bubble(&mut bubble_text, &bubble)?;
     }
-    let mut rng: StdRng = match seed {
-        Some(seed) => SeedableRng::seed_from_u64(seed),
-        None => SeedableRng::from_entropy(),
+
+    let shown = if montage.is_empty() {
+        std::slice::from_ref(&image_path)
+    } else {
+        &montage
     };
-    Ok(rng.gen_range(0..len))
-}
+    let mut composed = Vec::new();
+    if cli.position == BubblePosition::Top {
+        composed.append(&mut bubble_text);
+    }
 
-fn render_bubble(text: &str, term_cols: usize) -> Vec<String> {
-    let padding = 4usize;
-    if term_cols <= padding + 10 {
-        return vec![text.to_string()];
+    if cli.caption && image_pack.is_some() {
+        let stems: Vec<&str> = shown
+            .iter()
+            .filter_map(|path| path.file_stem().and_then(OsStr::to_str))
+            .collect();
+        if !stems.is_empty() {
+            let stem = stems.join("  ");
+            if colors == ChafaColors::None {
+                writeln!(composed, "{stem}")?;
+            } else {
+                writeln!(composed, "\x1b[2m{stem}\x1b[0m")?;
+            }
+        }
     }
 
-    let bubble_width = min(term_cols.saturating_sub(padding), DEFAULT_BUBBLE_MAX_WIDTH);
-    let wrapped = wrap_text_lines(text, bubble_width);
+    write!(composed, "{image_output}")?;
+    // chafa does not always end with a full reset, which can leave the
+    // prompt colored.
+    let reset_after = config.reset_after
+        && !html
+        && !no_color()
+        && cli.output.is_none()
+        && std::io::stdout().is_terminal();
+    if reset_after && !image_output.is_empty() {
+        write!(composed, "\x1b[0m")?;
+    }
+    let ends_line = image_output.is_empty() || image_output.ends_with('\n');
+    if !ends_line && (reset_after || cli.position == BubblePosition::Bottom) {
+        writeln!(composed)?;
+    }
 
-    if wrapped.is_empty() {
-        return Vec::new();
+    if cli.position == BubblePosition::Bottom {
+        composed.append(&mut bubble_text);
     }
 
-    let max_line_len = wrapped
-        .iter()
-        .map(|line| UnicodeWidthStr::width(line.as_str()))
-        .max()
-        .unwrap_or(0);
-    let mut lines = Vec::new();
-    lines.push(format!(" {}", "_".repeat(max_line_len + 2)));
-    if wrapped.len() == 1 {
-        lines.push(format!("< {} >", pad_line(&wrapped[0], max_line_len)));
+    if cli.credit {
+        for path in shown {
+            if let Some(pack) = pack_of(path) {
+                writeln!(composed, "{}", credit_line(&pack.meta, path))?;
+            }
+        }
+    }
+    if let Some(meta) = image_pack.map(|p| &p.meta) {
+        if show_image && config.show_attribution {
+            if let Some(url) = &meta.attribution_url {
+                writeln!(
+                    composed,
+                    "{}",
+                    attribution_line(&meta.name, url, colors != ChafaColors::None)
+                )?;
+            }
+        }
+    }
+    if html {
+        let text = String::from_utf8_lossy(&composed);
+        writeln!(
+            out,
+            "<pre class=\"leftysay\" style=\"font-family: monospace; line-height: 1;\">{}</pre>",
+            ansi_to_html(&text)
+        )?;
     } else {
-        for (idx, line) in wrapped.iter().enumerate() {
-            let (left, right) = match idx {
-                0 => ('/', '\\'),
-                i if i + 1 == wrapped.len() => ('\\', '/'),
-                _ => ('|', '|'),
-            };
-            lines.push(format!("{left} {} {right}", pad_line(line, max_line_len)));
+        if cli.vcenter {
+            let text_image =
+                !montage.is_empty() || effective_format(format) == ChafaFormat::Unicode;
+            let padding =
+                vcenter_padding(&composed, image_output, text_image, image_rows, term_rows);
+            out.write_all("\n".repeat(padding).as_bytes())?;
         }
+        out.write_all(&composed)?;
     }
-    lines.push(format!(" {}", "-".repeat(max_line_len + 2)));
+    out.flush()?;
 
-    append_tail(&mut lines, max_line_len + 2, term_cols);
+    if cli.stats {
+        write_stats(
+            &mut std::io::stderr(),
+            rendered.as_ref(),
+            &bubble,
+            image_output,
+        )?;
+    }
 
-    lines
+    Ok(())
 }
 
-fn pad_line(line: &str, width: usize) -> String {
-    let mut s = line.to_string();
-    let line_width = UnicodeWidthStr::width(line);
-    if line_width < width {
-        s.push_str(&" ".repeat(width - line_width));
+// Blank lines to print above the output so it sits in the middle of the
+// terminal. A graphics protocol image is one line of output but covers the
+// rows it was rendered into.
+fn vcenter_padding(
+    composed: &[u8],
+    image_output: &str,
+    text_image: bool,
+    image_rows: usize,
+    term_rows: usize,
+) -> usize {
+    let mut height = composed.iter().filter(|&&b| b == b'\n').count();
+    if !text_image && !image_output.is_empty() {
+        height += image_rows.saturating_sub(image_output.lines().count());
     }
-    s
+    term_rows.saturating_sub(height) / 2
 }
 
-fn append_tail(lines: &mut Vec<String>, bubble_inner_width: usize, term_cols: usize) {
-    let bubble_width = bubble_inner_width + 2;
-    let bubble_indent = 1usize;
-    let bubble_right = bubble_indent + bubble_width;
-    let mut start_col = bubble_right + 1;
-    if start_col + 1 >= term_cols {
-        start_col = bubble_indent + bubble_width.saturating_sub(1);
-    }
-
-    let tail = ["o", " o", "  o"];
-    for (i, segment) in tail.iter().enumerate() {
-        let spaces = start_col.saturating_add(i);
-        lines.push(format!("{:width$}{}", "", segment, width = spaces));
+fn write_stats<W: Write + ?Sized>(
+    out: &mut W,
+    rendered: Option<&RenderedImage>,
+    bubble: &[String],
+    image_output: &str,
+) -> Result<()> {
+    match rendered {
+        Some(rendered) if rendered.cache_hit => writeln!(out, "stats: cache hit")?,
+        Some(rendered) => writeln!(
+            out,
+            "stats: cache miss, chafa took {} ms",
+            rendered.elapsed.as_millis()
+        )?,
+        None => writeln!(out, "stats: image skipped")?,
     }
+    writeln!(out, "stats: render size {} bytes", image_output.len())?;
+    let lines: Vec<&str> = bubble
+        .iter()
+        .map(String::as_str)
+        .chain(image_output.lines())
+        .collect();
+    let cols = lines
+        .iter()
+        .map(|line| visible_width(line))
+        .max()
+        .unwrap_or(0);
+    writeln!(out, "stats: output {cols} cols x {} rows", lines.len())?;
+    Ok(())
 }
 
-fn wrap_text_lines(text: &str, width: usize) -> Vec<String> {
-    let mut lines = Vec::new();
-    let normalized = text.replace('\t', "    ");
-    for raw_line in normalized.lines() {
-        let trimmed = raw_line.trim_end();
-        if trimmed.is_empty() {
-            lines.push(String::new());
-            continue;
-        }
-        for line in wrap(trimmed, width) {
-            lines.push(line.into_owned());
+// Converts SGR colors and OSC 8 links to HTML, escaping the text and
+// dropping any other escape sequences.
+fn ansi_to_html(text: &str) -> String {
+    let mut out = String::with_capacity(text.len() * 2);
+    let (mut fg, mut bg, mut inverse): (Option<String>, Option<String>, bool) = (None, None, false);
+    let mut span_open = false;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\x1b' => match chars.next() {
+                Some('[') => {
+                    let mut params = String::new();
+                    let mut last = None;
+                    for c in chars.by_ref() {
+                        if ('@'..='~').contains(&c) {
+                            last = Some(c);
+                            break;
+                        }
+                        params.push(c);
+                    }
+                    if last != Some('m') {
+                        continue;
+                    }
+                    apply_sgr(&params, &mut fg, &mut bg, &mut inverse);
+                    if span_open {
+                        out.push_str("</span>");
+                        span_open = false;
+                    }
+                    let (fg, bg) = if inverse { (&bg, &fg) } else { (&fg, &bg) };
+                    let mut style = String::new();
+                    if let Some(color) = fg {
+                        style.push_str(&format!("color:{color};"));
+                    }
+                    if let Some(color) = bg {
+                        style.push_str(&format!("background-color:{color};"));
+                    }
+                    if !style.is_empty() {
+                        out.push_str(&format!("<span style=\"{style}\">"));
+                        span_open = true;
+                    }
+                }
+                Some(']') => {
+                    let mut body = String::new();
+                    while let Some(c) = chars.next() {
+                        if c == '\x07' {
+                            break;
+                        }
+                        if c == '\x1b' && chars.peek() == Some(&'\\') {
+                            chars.next();
+                            break;
+                        }
+                        body.push(c);
+                    }
+                    if let Some(url) = body.strip_prefix("8;;") {
+                        if url.is_empty() {
+                            out.push_str("</a>");
+                        } else {
+                            out.push_str(&format!("<a href=\"{}\">", html_escape(url)));
+                        }
+                    }
+                }
+                _ => {}
+            },
+            _ => push_html_char(&mut out, c),
         }
     }
-    if lines.is_empty() {
-        lines.push(String::new());
+    if span_open {
+        out.push_str("</span>");
     }
-    lines
+    out
 }
 
-fn render_image(chafa: &Path, image: &Path, options: RenderOptions) -> Result<String> {
-    let cache_dir = cache_dir();
-    let cache_key = cache_key(
-        image,
-        options.cols,
-        options.rows,
-        options.format,
-        options.colors,
-        options.animate,
-    )?;
-    let cache_path = cache_dir.join(format!("{cache_key}.{CACHE_FILE_EXT}"));
-
-    if options.cache_enabled && cache_path.exists() {
-        let contents = fs::read_to_string(&cache_path)?;
-        // Touch file for LRU by rewriting.
-        fs::write(&cache_path, &contents)?;
-        return Ok(contents);
+fn apply_sgr(params: &str, fg: &mut Option<String>, bg: &mut Option<String>, inverse: &mut bool) {
+    let codes: Vec<u32> = params
+        .split(';')
+        .map(|code| code.parse().unwrap_or(0))
+        .collect();
+    let mut iter = codes.into_iter();
+    while let Some(code) = iter.next() {
+        match code {
+            0 => {
+                *fg = None;
+                *bg = None;
+                *inverse = false;
+            }
+            7 => *inverse = true,
+            27 => *inverse = false,
+            30..=37 | 90..=97 => *fg = Some(hex_color(ansi_palette(basic_index(code - 30)))),
+            40..=47 | 100..=107 => *bg = Some(hex_color(ansi_palette(basic_index(code - 40)))),
+            38 | 48 => {
+                let color = match iter.next() {
+                    Some(2) => {
+                        let mut channel = || iter.next().unwrap_or(0).min(255) as u8;
+                        Some((channel(), channel(), channel()))
+                    }
+                    Some(5) => iter.next().map(|n| ansi_palette(n.min(255) as u8)),
+                    _ => None,
+                };
+                let target = if code == 38 { &mut *fg } else { &mut *bg };
+                *target = color.map(hex_color);
+            }
+            39 => *fg = None,
+            49 => *bg = None,
+            _ => {}
+        }
     }
+}
 
-    let output = run_chafa(
-        chafa,
-        image,
-        options.cols,
-        options.rows,
-        options.format,
-        options.colors,
-        options.animate,
-    )?;
-
-    if options.cache_enabled {
-        fs::create_dir_all(&cache_dir)?;
-        let mut file = fs::File::create(&cache_path)?;
-        file.write_all(output.as_bytes())?;
-        enforce_cache_limit(&cache_dir, options.cache_max_mb * 1024 * 1024)?;
+// Maps the offset of a 30-37/90-97 style code to a palette index.
+fn basic_index(offset: u32) -> u8 {
+    if offset >= 60 {
+        (offset - 60 + 8) as u8
+    } else {
+        offset as u8
     }
-
-    Ok(output)
 }
 
-fn run_chafa(
-    chafa: &Path,
-    image: &Path,
-    cols: usize,
-    rows: usize,
-    format: ChafaFormat,
-    colors: ChafaColors,
-    animate: bool,
-) -> Result<String> {
-    let output = run_chafa_once(chafa, image, cols, rows, format, colors, animate)?;
-    if output.status.success() {
-        return Ok(String::from_utf8_lossy(&output.stdout).to_string());
+// The xterm 256-color palette.
+fn ansi_palette(index: u8) -> (u8, u8, u8) {
+    const BASIC: [(u8, u8, u8); 16] = [
+        (0, 0, 0),
+        (205, 0, 0),
+        (0, 205, 0),
+        (205, 205, 0),
+        (0, 0, 238),
+        (205, 0, 205),
+        (0, 205, 205),
+        (229, 229, 229),
+        (127, 127, 127),
+        (255, 0, 0),
+        (0, 255, 0),
+        (255, 255, 0),
+        (92, 92, 255),
+        (255, 0, 255),
+        (0, 255, 255),
+        (255, 255, 255),
+    ];
+    match index {
+        0..=15 => BASIC[index as usize],
+        16..=231 => {
+            let i = index - 16;
+            let level = |v: u8| if v == 0 { 0 } else { 55 + v * 40 };
+            (level(i / 36), level((i / 6) % 6), level(i % 6))
+        }
+        _ => {
+            let gray = 8 + (index - 232) * 10;
+            (gray, gray, gray)
+        }
     }
+}
 
-    let mut last_err = String::from_utf8_lossy(&output.stderr).to_string();
-    let mut fallback_format = format;
-    let mut fallback_colors = colors;
+fn hex_color((red, green, blue): (u8, u8, u8)) -> String {
+    format!("#{red:02x}{green:02x}{blue:02x}")
+}
 
-    if matches!(format, ChafaFormat::Auto) {
-        fallback_format = ChafaFormat::Unicode;
+fn html_escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        push_html_char(&mut out, c);
     }
-    if matches!(colors, ChafaColors::Auto) {
-        fallback_colors = ChafaColors::Truecolor;
+    out
+}
+
+fn push_html_char(out: &mut String, c: char) {
+    match c {
+        '&' => out.push_str("&amp;"),
+        '<' => out.push_str("&lt;"),
+        '>' => out.push_str("&gt;"),
+        '"' => out.push_str("&quot;"),
+        _ => out.push(c),
     }
+}
 
-    if fallback_format != format || fallback_colors != colors {
-        let retry = run_chafa_once(
-            chafa,
-            image,
-            cols,
-            rows,
-            fallback_format,
-            fallback_colors,
-            animate,
-        )?;
-        if retry.status.success() {
-            return Ok(String::from_utf8_lossy(&retry.stdout).to_string());
+// Prints the bubble while `render` runs on another thread. If the render
+// fails, the bubble is erased again, so the terminal looks the same as when
+// nothing is printed before the image.
+fn render_behind_bubble<W: Write + ?Sized>(
+    out: &mut W,
+    bubble_text: &[u8],
+    render: impl FnOnce() -> Result<RenderedImage> + Send,
+) -> Result<Result<RenderedImage>> {
+    thread::scope(|scope| {
+        let handle = scope.spawn(render);
+        out.write_all(bubble_text)?;
+        out.flush()?;
+        let rendered = handle
+            .join()
+            .unwrap_or_else(|panic| std::panic::resume_unwind(panic));
+        let rows = bubble_text.iter().filter(|&&b| b == b'\n').count();
+        if rendered.is_err() && rows > 0 {
+            write!(out, "\x1b[{rows}A\x1b[J")?;
+            out.flush()?;
+        }
+        Ok(rendered)
+    })
+}
+
+fn open_output(path: Option<&Path>) -> Result<Box<dyn Write>> {
+    match path {
+        Some(path) => {
+            let file = fs::File::create(path)
+                .with_context(|| format!("creating output file {}", path.display()))?;
+            Ok(Box::new(std::io::BufWriter::new(file)))
         }
-        last_err = String::from_utf8_lossy(&retry.stderr).to_string();
+        None => Ok(Box::new(std::io::stdout().lock())),
     }
+}
 
-    Err(anyhow!("chafa failed: {last_err}"))
+fn credit_line(meta: &PackMeta, image: &Path) -> String {
+    let file = image
+        .file_name()
+        .map(|name| name.to_string_lossy())
+        .unwrap_or_default();
+    format!("{} {} ({}) - {file}", meta.name, meta.version, meta.license)
 }
 
-fn run_chafa_once(
-    chafa: &Path,
-    image: &Path,
-    cols: usize,
-    rows: usize,
-    format: ChafaFormat,
-    colors: ChafaColors,
-    animate: bool,
-) -> Result<std::process::Output> {
-    let mut cmd = Command::new(chafa);
-    cmd.arg(image)
-        .arg("--format")
-        .arg(format.as_arg())
-        .arg("--colors")
-        .arg(colors.as_arg())
-        .arg("--size")
-        .arg(format!("{cols}x{rows}"));
-    if animate {
-        cmd.arg("--animate");
+fn attribution_line(name: &str, url: &str, dim: bool) -> String {
+    let link = format!("\x1b]8;;{url}\x1b\\art: {name}\x1b]8;;\x1b\\");
+    if dim {
+        format!("\x1b[2m{link}\x1b[0m")
+    } else {
+        link
     }
-
-    cmd.output().with_context(|| "running chafa")
 }
 
-fn cache_key(
-    image: &Path,
-    cols: usize,
-    rows: usize,
-    format: ChafaFormat,
+// The gradient only needs colors and somewhere to print escapes; it does not
+// depend on the image being shown.
+fn rainbow_phase(
+    cli: &Cli,
+    escapes_allowed: bool,
     colors: ChafaColors,
-    animate: bool,
-) -> Result<String> {
-    let mut hasher = blake3::Hasher::new();
-    let meta = fs::metadata(image).with_context(|| "reading image metadata")?;
-    let mtime = meta
-        .modified()
-        .ok()
-        .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
-        .map(|d| d.as_secs())
-        .unwrap_or(0);
-    hasher.update(image.to_string_lossy().as_bytes());
-    hasher.update(&mtime.to_le_bytes());
-    hasher.update(&cols.to_le_bytes());
-    hasher.update(&rows.to_le_bytes());
-    hasher.update(format.as_arg().as_bytes());
-    hasher.update(colors.as_arg().as_bytes());
-    hasher.update(&[animate as u8]);
-    Ok(hasher.finalize().to_hex().to_string())
+    seed: Option<u64>,
+) -> Option<f64> {
+    (cli.rainbow && escapes_allowed && colors != ChafaColors::None)
+        .then(|| seeded_rng(cli.rainbow_seed.or(seed)).gen_range(0.0..std::f64::consts::TAU))
 }
 
-fn cache_dir() -> PathBuf {
-    ProjectDirs::from("", "", "leftysay")
-        .map(|proj| proj.cache_dir().to_path_buf())
-        .unwrap_or_else(|| PathBuf::from(".cache/leftysay"))
+fn image_row_budget(term_rows: usize, max_height_ratio: f32, bubble_height: usize) -> usize {
+    let max_image_rows = ((term_rows as f32) * max_height_ratio).floor() as usize;
+    let remaining_rows = term_rows.saturating_sub(bubble_height + 1);
+    min(max_image_rows, remaining_rows)
 }
 
-fn enforce_cache_limit(cache_dir: &Path, max_bytes: u64) -> Result<()> {
-    if !cache_dir.exists() {
-        return Ok(());
+fn terminal_dimensions() -> (usize, usize) {
+    let env_cols = env_dimension("COLUMNS");
+    let env_rows = env_dimension("LINES");
+    if let (Some(cols), Some(rows)) = (env_cols, env_rows) {
+        return (cols, rows);
     }
 
-    let mut entries: Vec<_> = fs::read_dir(cache_dir)
-        .with_context(|| format!("reading cache dir {}", cache_dir.display()))?
-        .filter_map(Result::ok)
-        .collect();
-
-    let mut total_size: u64 = entries
-        .iter()
-        .filter_map(|entry| entry.metadata().ok().map(|m| m.len()))
-        .sum();
+    let (cols, rows) = if let Some((Width(w), Height(h))) = terminal_size() {
+        (w as usize, h as usize)
+    } else {
+        (80, 24)
+    };
+    (env_cols.unwrap_or(cols), env_rows.unwrap_or(rows))
+}
+
+fn no_color() -> bool {
+    std::env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty())
+}
+
+fn disabled_by_env() -> bool {
+    std::env::var("LEFTYSAY_DISABLE")
+        .is_ok_and(|value| matches!(value.trim().to_lowercase().as_str(), "1" | "true" | "yes"))
+}
+
+fn env_dimension(name: &str) -> Option<usize> {
+    std::env::var(name)
+        .ok()
+        .and_then(|value| value.trim().parse::<usize>().ok())
+        .filter(|value| *value > 0)
+}
+
+fn dump_config<W: Write + ?Sized>(out: &mut W, config: &Config) -> Result<()> {
+    let toml = toml::to_string(config).context("serializing config")?;
+    write!(out, "{toml}")?;
+    Ok(())
+}
+
+// TOML only has 64-bit floats, and widening 0.55f32 would print
+// 0.550000011920929; go through the shortest decimal form instead.
+fn serialize_short_f32<S: serde::Serializer>(
+    value: &f32,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(value.to_string().parse().unwrap_or(f64::from(*value)))
+}
+
+/// Folds flags that have a config equivalent into `config`, so the rest of the
+/// run (and `--dump-config`) only has to look at one place.
+fn apply_cli_overrides(config: &mut Config, cli: &Cli) {
+    config.quiet |= cli.quiet;
+    config.daily |= cli.daily;
+    config.animate |= cli.animate;
+    config.ascii_borders |= cli.ascii;
+    if cli.no_cache {
+        config.cache = false;
+    } else if cli.cache {
+        config.cache = true;
+    }
+    if let Some(pack) = &cli.pack {
+        config.default_pack = pack.clone();
+    }
+    if let Some(eyes) = &cli.eyes {
+        config.eyes = eyes.clone();
+    }
+    if let Some(tongue) = &cli.tongue {
+        config.tongue = tongue.clone();
+    }
+    if let Some(format) = cli.format {
+        config.format = format;
+    }
+    if let Some(colors) = cli.colors {
+        config.colors = colors;
+    }
+    if let Some(ratio) = cli.max_height_ratio {
+        config.max_height_ratio = ratio;
+    }
+    if let Some(sizing) = cli.sizing {
+        config.sizing = sizing;
+    }
+    if let Some(dither) = cli.dither {
+        config.dither = Some(dither);
+    }
+    if let Some(grain) = &cli.dither_grain {
+        config.dither_grain = Some(grain.clone());
+    }
+    if let Some(symbols) = cli.symbols {
+        config.symbols = Some(symbols);
+    }
+    if let Some(bg) = &cli.bg {
+        config.bg = Some(bg.clone());
+    }
+    if let Some(width) = cli.bubble_width {
+        config.bubble_max_width = width;
+    }
+    if let Some(align) = cli.bubble_align {
+        config.bubble_align = align;
+    }
+    if let Some(fps) = cli.fps {
+        config.fps = fps;
+    }
+    if let Some(loops) = cli.loops {
+        config.loops = loops;
+    }
+    if let Some(timeout) = cli.timeout {
+        config.chafa_timeout_ms = timeout;
+    }
+    config
+        .chafa_extra_args
+        .extend(cli.chafa_args.iter().cloned());
+    normalize_config(config);
+}
+
+fn selected_profile(cli: &Cli) -> Option<String> {
+    cli.profile
+        .clone()
+        .or_else(|| std::env::var("LEFTYSAY_PROFILE").ok())
+        .filter(|name| !name.is_empty())
+}
+
+fn load_config(explicit_path: Option<&Path>, profile: Option<&str>) -> Result<Config> {
+    let explicit_path = explicit_path
+        .map(Path::to_path_buf)
+        .or_else(|| std::env::var_os("LEFTYSAY_CONFIG").map(PathBuf::from));
+    let user_path = match explicit_path {
+        Some(path) => {
+            if !path.exists() {
+                return Err(anyhow!("config file not found: {}", path.display()));
+            }
+            Some(path)
+        }
+        None => ProjectDirs::from("", "", "leftysay")
+            .map(|proj_dirs| proj_dirs.config_dir().join("config.toml"))
+            .filter(|path| path.exists()),
+    };
+    let files: Vec<PathBuf> = system_config_path()
+        .filter(|path| path.exists())
+        .into_iter()
+        .chain(user_path)
+        .collect();
+    if files.is_empty() {
+        if let Some(name) = profile {
+            return Err(anyhow!("profile not found: {name} (no config file)"));
+        }
+        info!("no config file, using defaults");
+        return Ok(Config::default());
+    }
+    load_config_files(&files, profile)
+}
+
+// The system config is the baseline an administrator sets for everyone.
+// `LEFTYSAY_SYSTEM_CONFIG` points elsewhere, or disables it when empty.
+fn system_config_path() -> Option<PathBuf> {
+    if let Some(path) = std::env::var_os("LEFTYSAY_SYSTEM_CONFIG") {
+        return (!path.is_empty()).then(|| PathBuf::from(path));
+    }
+    if cfg!(windows) {
+        std::env::var_os("ProgramData")
+            .map(|dir| PathBuf::from(dir).join("leftysay").join("config.toml"))
+    } else if cfg!(target_os = "macos") {
+        Some(PathBuf::from(
+            "/Library/Application Support/leftysay/config.toml",
+        ))
+    } else {
+        Some(PathBuf::from("/etc/leftysay/config.toml"))
+    }
+}
+
+// Later files win field by field, including inside tables and profiles.
+fn load_config_files(files: &[PathBuf], profile: Option<&str>) -> Result<Config> {
+    let mut table = toml::Table::new();
+    for path in files {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("reading config {}", path.display()))?;
+        let layer: toml::Table = toml::from_str(&contents)
+            .with_context(|| format!("parsing config {}", path.display()))?;
+        merge_tables(&mut table, layer);
+        info!("config: {}", path.display());
+    }
+    if let Some(name) = profile {
+        info!("config profile: {name}");
+    }
+    let config_path = files
+        .iter()
+        .map(|path| path.display().to_string())
+        .collect::<Vec<_>>()
+        .join(" + ");
+    let mut config = config_from_table(table, profile)?;
+    config.files = files.to_vec();
+    normalize_config(&mut config);
+    config
+        .bubble
+        .chars
+        .glyphs()
+        .with_context(|| format!("invalid config {config_path}"))?;
+    if let Some(grain) = &config.dither_grain {
+        parse_dither_grain(grain).with_context(|| format!("invalid config {config_path}"))?;
+    }
+    if let Some(bg) = &config.bg {
+        parse_hex_color(bg).with_context(|| format!("invalid config {config_path}"))?;
+    }
+    Ok(config)
+}
+
+// Out-of-range values fall back to the defaults, whether they came from a
+// config file or a flag.
+fn normalize_config(config: &mut Config) {
+    if config.max_height_ratio <= 0.0 || config.max_height_ratio > 1.0 {
+        config.max_height_ratio = DEFAULT_MAX_HEIGHT_RATIO;
+    }
+    if config.cache_max_mb == 0 {
+        config.cache_max_mb = DEFAULT_CACHE_MAX_MB;
+    }
+    if config.chafa_timeout_ms == 0 {
+        config.chafa_timeout_ms = DEFAULT_CHAFA_TIMEOUT_MS;
+    }
+}
+
+fn parse_dither_grain(value: &str) -> Result<String> {
+    let valid = |part: &str| matches!(part, "1" | "2" | "4" | "8");
+    let ok = match value.split_once('x') {
+        Some((width, height)) => valid(width) && valid(height),
+        None => valid(value),
+    };
+    if ok {
+        Ok(value.to_string())
+    } else {
+        Err(anyhow!(
+            "dither_grain must be W or WxH with each of 1, 2, 4 or 8, got {value:?}"
+        ))
+    }
+}
+
+fn parse_hex_color(value: &str) -> Result<String> {
+    let digits = value.strip_prefix('#').unwrap_or(value);
+    if digits.len() == 6 && digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        Ok(format!("#{}", digits.to_ascii_lowercase()))
+    } else {
+        Err(anyhow!("expected a hex color like #282828, got {value:?}"))
+    }
+}
+
+fn config_from_table(mut table: toml::Table, profile: Option<&str>) -> Result<Config> {
+    let profiles = match table.remove("profiles") {
+        Some(toml::Value::Table(profiles)) => profiles,
+        Some(_) => return Err(anyhow!("parsing config: profiles must be a table")),
+        None => toml::Table::new(),
+    };
+    if let Some(name) = profile {
+        match profiles.get(name) {
+            Some(toml::Value::Table(overrides)) => merge_tables(&mut table, overrides.clone()),
+            _ => {
+                let available: Vec<&str> = profiles.keys().map(String::as_str).collect();
+                return Err(anyhow!(
+                    "profile not found: {name} (available: {})",
+                    available.join(", ")
+                ));
+            }
+        }
+    }
+    table.try_into().context("parsing config")
+}
+
+fn merge_tables(base: &mut toml::Table, overrides: toml::Table) {
+    for (key, value) in overrides {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(value)) => {
+                merge_tables(base, value)
+            }
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+fn find_chafa() -> Result<PathBuf> {
+    if let Ok(path) = std::env::var("LEFTYSAY_CHAFA") {
+        return Ok(PathBuf::from(path));
+    }
+
+    let candidate = if cfg!(windows) { "chafa.exe" } else { "chafa" };
+    if let Some(paths) = std::env::var_os("PATH") {
+        for dir in std::env::split_paths(&paths) {
+            let full = dir.join(candidate);
+            if full.is_file() {
+                return Ok(full);
+            }
+        }
+    }
+
+    let install_hint = match std::env::consts::OS {
+        "linux" => "Install: sudo apt install chafa (Debian/Ubuntu) or sudo pacman -S chafa (Arch)",
+        "macos" => "Install: brew install chafa",
+        _ => "Install chafa from your package manager",
+    };
+    Err(anyhow!("leftysay requires chafa. {install_hint}"))
+}
+
+fn chafa_version(chafa: &Path) -> Option<String> {
+    let output = Command::new(chafa).arg("--version").output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .next()
+        .map(|line| line.trim().to_string())
+        .filter(|line| !line.is_empty())
+}
+
+fn parse_version(text: &str) -> Option<(u32, u32, u32)> {
+    let token = text
+        .split_whitespace()
+        .find(|token| token.starts_with(|c: char| c.is_ascii_digit()))?;
+    let mut parts = token
+        .split(|c: char| !c.is_ascii_digit())
+        .map(|part| part.parse::<u32>().ok());
+    let major = parts.next()??;
+    let minor = parts.next().flatten().unwrap_or(0);
+    let patch = parts.next().flatten().unwrap_or(0);
+    Some((major, minor, patch))
+}
+
+fn min_chafa_version(format: ChafaFormat) -> Option<(u32, u32, u32)> {
+    match format {
+        ChafaFormat::Sixel => Some((1, 4, 0)),
+        ChafaFormat::Kitty | ChafaFormat::Iterm2 => Some((1, 8, 0)),
+        ChafaFormat::Auto | ChafaFormat::Unicode => None,
+    }
+}
+
+fn pack_search_paths() -> Vec<PathBuf> {
+    let mut paths = Vec::new();
+
+    if let Ok(extra) = std::env::var("LEFTYSAY_PACKS_DIR") {
+        paths.push(PathBuf::from(extra));
+    }
+
+    if let Some(proj_dirs) = ProjectDirs::from("", "", "leftysay") {
+        paths.push(proj_dirs.data_dir().join("packs"));
+    }
+
+    if cfg!(target_os = "macos") {
+        let brew_prefixes = [
+            std::env::var("HOMEBREW_PREFIX").ok(),
+            Some("/opt/homebrew".to_string()),
+            Some("/usr/local".to_string()),
+        ];
+        for prefix in brew_prefixes.iter().flatten() {
+            let candidate = Path::new(prefix).join("share/leftysay/packs");
+            if candidate.exists() {
+                paths.push(candidate);
+            }
+        }
+    } else if cfg!(target_os = "linux") {
+        paths.push(PathBuf::from("/usr/share/leftysay/packs"));
+    }
+
+    if Path::new("packs").exists() {
+        paths.push(PathBuf::from("packs"));
+    }
+
+    paths
+}
+
+fn scan_packs(config: &Config) -> Result<PackScan> {
+    scan_pack_roots(&pack_search_paths(), config)
+}
+
+fn load_packs(config: &Config, use_index: bool, refresh: bool) -> Result<PackScan> {
+    let bases = pack_search_paths();
+    if !use_index {
+        return scan_pack_roots(&bases, config);
+    }
+
+    let index_path = cache_dir(config).join(PACK_INDEX_FILE);
+    let key = pack_index_key(&bases, config);
+    if !refresh {
+        if let Some(scan) = read_pack_index(&index_path, &key) {
+            return Ok(scan);
+        }
+    }
+
+    let scan = scan_pack_roots(&bases, config)?;
+    // The index is only an optimization, so failing to write it is not fatal.
+    let _ = write_pack_index(&index_path, key, &bases, &scan, config);
+    Ok(scan)
+}
+
+fn pack_index_key(bases: &[PathBuf], config: &Config) -> String {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(env!("CARGO_PKG_VERSION").as_bytes());
+    if let Ok(cwd) = std::env::current_dir() {
+        hasher.update(cwd.to_string_lossy().as_bytes());
+        hasher.update(&[0]);
+    }
+    for base in bases {
+        hasher.update(base.to_string_lossy().as_bytes());
+        hasher.update(&[0]);
+    }
+    for ext in &config.extra_image_exts {
+        hasher.update(ext.as_bytes());
+        hasher.update(&[0]);
+    }
+    hasher.update(&[config.follow_symlinks as u8]);
+    hasher.update(&[config.dedup_images as u8]);
+    hasher.finalize().to_hex().to_string()
+}
+
+fn pack_index_stamps(
+    bases: &[PathBuf],
+    scan: &PackScan,
+    config: &Config,
+) -> Vec<(PathBuf, Option<u128>)> {
+    let mut paths: std::collections::BTreeSet<PathBuf> = bases.iter().cloned().collect();
+    // Every directory a `pack.toml` could appear in, so a new pack in a
+    // directory that had none still invalidates the index.
+    for base in bases {
+        paths.extend(
+            WalkDir::new(base)
+                .max_depth(PACK_DISCOVERY_DEPTH - 1)
+                .follow_links(config.follow_symlinks)
+                .into_iter()
+                .filter_map(Result::ok)
+                .filter(|entry| entry.file_type().is_dir())
+                .map(|entry| entry.into_path()),
+        );
+    }
+    let mut add_with_ancestors = |path: &Path| {
+        for ancestor in path
+            .ancestors()
+            .take_while(|a| !bases.iter().any(|b| b == a))
+        {
+            if !paths.insert(ancestor.to_path_buf()) {
+                break;
+            }
+        }
+    };
+    for pack in &scan.packs {
+        add_with_ancestors(&pack.root.join("pack.toml"));
+        add_with_ancestors(&pack.root.join("messages.txt"));
+        for image in &pack.images {
+            if let Some(parent) = image.parent() {
+                add_with_ancestors(parent);
+            }
+        }
+    }
+    for empty in &scan.empty {
+        add_with_ancestors(&empty.images_dir);
+    }
+    // Dedup depends on file contents, which a directory's mtime does not
+    // cover, so an image edited in place must invalidate the index too.
+    if config.dedup_images {
+        for pack in &scan.packs {
+            paths.extend(pack.images.iter().cloned());
+            paths.extend(pack.duplicates.values().flatten().cloned());
+        }
+    }
+
+    paths
+        .into_iter()
+        .map(|path| {
+            let mtime = path_mtime(&path);
+            (path, mtime)
+        })
+        .collect()
+}
+
+fn path_mtime(path: &Path) -> Option<u128> {
+    fs::metadata(path)
+        .and_then(|meta| meta.modified())
+        .ok()
+        .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+        .map(|d| d.as_nanos())
+}
+
+fn read_pack_index(index_path: &Path, key: &str) -> Option<PackScan> {
+    let contents = fs::read_to_string(index_path).ok()?;
+    let index: PackIndex = serde_json::from_str(&contents).ok()?;
+    if index.key != key {
+        return None;
+    }
+    let fresh = index
+        .stamps
+        .iter()
+        .all(|(path, mtime)| path_mtime(path) == *mtime);
+    fresh.then_some(index.scan)
+}
+
+fn write_pack_index(
+    index_path: &Path,
+    key: String,
+    bases: &[PathBuf],
+    scan: &PackScan,
+    config: &Config,
+) -> Result<()> {
+    let index = PackIndex {
+        key,
+        stamps: pack_index_stamps(bases, scan, config),
+        scan: scan.clone(),
+    };
+    if let Some(parent) = index_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(index_path, serde_json::to_string(&index)?)?;
+    Ok(())
+}
+
+fn scan_pack_roots(bases: &[PathBuf], config: &Config) -> Result<PackScan> {
+    // Collect pack.toml files in search-path order first so that the parallel
+    // loading below can't change which pack wins a name collision.
+    let mut meta_paths = Vec::new();
+    let mut bare_bases = Vec::new();
+    for base in bases {
+        if !base.exists() {
+            continue;
+        }
+        let found = meta_paths.len();
+        meta_paths.extend(
+            WalkDir::new(base)
+                .max_depth(PACK_DISCOVERY_DEPTH)
+                .follow_links(config.follow_symlinks)
+                .into_iter()
+                .filter_map(Result::ok)
+                .filter(|entry| entry.file_name() == "pack.toml")
+                .map(|entry| entry.into_path()),
+        );
+        if meta_paths.len() == found {
+            bare_bases.push(base);
+        }
+    }
+
+    type Loaded = (
+        PackMeta,
+        PathBuf,
+        Vec<PathBuf>,
+        BTreeMap<PathBuf, Vec<PathBuf>>,
+    );
+    let loaded: Vec<Result<Loaded>> = meta_paths
+        .par_iter()
+        .map(|meta_path| {
+            let pack_root = meta_path.parent().unwrap_or(meta_path).to_path_buf();
+            let meta = read_pack_meta(meta_path)?;
+            let mut images = collect_images(
+                &pack_root,
+                &meta,
+                &config.extra_image_exts,
+                config.follow_symlinks,
+            )?;
+            let duplicates = if config.dedup_images {
+                dedup_images(&mut images)
+            } else {
+                BTreeMap::new()
+            };
+            Ok((meta, pack_root, images, duplicates))
+        })
+        .collect();
+
+    let mut scan = PackScan::default();
+    let mut seen: std::collections::HashMap<String, PathBuf> = std::collections::HashMap::new();
+    for result in loaded {
+        let (meta, pack_root, images, duplicates) = result?;
+        if let Some(winner) = seen.get(&meta.name) {
+            scan.shadowed.push(ShadowedPack {
+                name: meta.name,
+                winner: winner.clone(),
+                shadowed: pack_root,
+            });
+            continue;
+        }
+        if images.is_empty() {
+            scan.empty.push(EmptyPack {
+                images_dir: pack_root.join(&meta.images_dir),
+                name: meta.name,
+            });
+            continue;
+        }
+        let messages = read_messages(&pack_root);
+        seen.insert(meta.name.clone(), pack_root.clone());
+        scan.packs.push(Pack {
+            meta,
+            root: pack_root,
+            images,
+            duplicates,
+            messages,
+        });
+    }
+
+    // A search path with images but no pack.toml anywhere is used as an ad-hoc pack.
+    for base in bare_bases {
+        if let Some(pack) = implicit_pack(base, config)? {
+            match seen.get(&pack.meta.name) {
+                Some(winner) => scan.shadowed.push(ShadowedPack {
+                    name: pack.meta.name,
+                    winner: winner.clone(),
+                    shadowed: pack.root,
+                }),
+                None => {
+                    seen.insert(pack.meta.name.clone(), pack.root.clone());
+                    scan.packs.push(pack);
+                }
+            }
+        }
+    }
+
+    scan.reserved = scan
+        .packs
+        .iter()
+        .filter(|pack| is_reserved_pack_name(&pack.meta.name))
+        .map(|pack| ReservedPack {
+            name: pack.meta.name.clone(),
+            root: pack.root.clone(),
+        })
+        .collect();
+    Ok(scan)
+}
+
+fn is_reserved_pack_name(name: &str) -> bool {
+    name == ALL_PACKS || name == RANDOM_PACK || name.starts_with(DIR_PACK_PREFIX)
+}
+
+fn implicit_pack(dir: &Path, config: &Config) -> Result<Option<Pack>> {
+    let name = dir
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| dir.display().to_string());
+    let meta = PackMeta {
+        name,
+        version: "0.0.0".to_string(),
+        license: "unknown".to_string(),
+        description: format!("Images in {}", dir.display()),
+        images_dir: ".".to_string(),
+        include: Vec::new(),
+        exclude: Vec::new(),
+        speaker: None,
+        attribution_url: None,
+        enabled: true,
+        frames: BTreeMap::new(),
+        format: None,
+        colors: None,
+    };
+    let mut images = collect_images(dir, &meta, &config.extra_image_exts, config.follow_symlinks)?;
+    if images.is_empty() {
+        return Ok(None);
+    }
+    let duplicates = if config.dedup_images {
+        dedup_images(&mut images)
+    } else {
+        BTreeMap::new()
+    };
+    Ok(Some(Pack {
+        meta,
+        root: dir.to_path_buf(),
+        images,
+        duplicates,
+        messages: read_messages(dir),
+    }))
+}
+
+fn describe_shadowed_pack(shadowed: &ShadowedPack) -> String {
+    format!(
+        "pack \"{}\" in {} is shadowed by {} (the first search path wins)",
+        shadowed.name,
+        shadowed.shadowed.display(),
+        shadowed.winner.display()
+    )
+}
+
+fn describe_reserved_pack(reserved: &ReservedPack) -> String {
+    format!(
+        "pack \"{}\" in {} cannot be picked by name: `--pack {}` has another meaning; rename it",
+        reserved.name,
+        reserved.root.display(),
+        reserved.name
+    )
+}
+
+fn describe_empty_pack(empty: &EmptyPack) -> String {
+    format!(
+        "pack \"{}\" skipped: no supported images in {}",
+        empty.name,
+        empty.images_dir.display()
+    )
+}
+
+fn read_pack_meta(path: &Path) -> Result<PackMeta> {
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("reading pack meta {}", path.display()))?;
+    parse_pack_meta(&contents).with_context(|| format!("invalid pack meta {}", path.display()))
+}
+
+// Checks the required fields by hand first, since serde's errors for them
+// don't say how to fix the file.
+fn parse_pack_meta(contents: &str) -> Result<PackMeta> {
+    let table: toml::Table =
+        toml::from_str(contents).map_err(|e| anyhow!("not valid TOML: {}", e.message()))?;
+    let required = [
+        ("name", "\"my-pack\""),
+        ("version", "\"0.1.0\""),
+        ("license", "\"CC0-1.0\""),
+        ("description", "\"What this pack contains\""),
+        ("images_dir", "\"images\""),
+    ];
+    for (field, example) in required {
+        match table.get(field) {
+            None => {
+                return Err(anyhow!(
+                    "missing required field `{field}` (add e.g. `{field} = {example}`)"
+                ))
+            }
+            Some(toml::Value::String(_)) => {}
+            Some(other) => {
+                return Err(anyhow!(
+                "field `{field}` must be a string, found {} (quote it, e.g. `{field} = {example}`)",
+                other.type_str()
+            ))
+            }
+        }
+    }
+    table
+        .try_into()
+        .map_err(|e: toml::de::Error| anyhow!("{}", e.message()))
+}
+
+fn collect_images(
+    pack_root: &Path,
+    meta: &PackMeta,
+    extra_exts: &[String],
+    follow_links: bool,
+) -> Result<Vec<PathBuf>> {
+    let dir = match meta.images_dir.as_str() {
+        "." => pack_root.to_path_buf(),
+        images_dir => pack_root.join(images_dir),
+    };
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+    let include = build_globset(&meta.include)
+        .with_context(|| format!("parsing include globs of pack {}", meta.name))?;
+    let exclude = build_globset(&meta.exclude)
+        .with_context(|| format!("parsing exclude globs of pack {}", meta.name))?;
+
+    // With follow_links, WalkDir reports symlink loops as errors, which are skipped.
+    let mut images: Vec<PathBuf> = WalkDir::new(&dir)
+        .follow_links(follow_links)
+        .into_iter()
+        .filter_map(Result::ok)
+        .filter(|entry| entry.file_type().is_file())
+        .filter(|entry| is_supported_image(entry.path(), extra_exts))
+        .filter(|entry| {
+            let relative = entry.path().strip_prefix(&dir).unwrap_or(entry.path());
+            include.as_ref().is_none_or(|set| set.is_match(relative))
+                && !exclude.as_ref().is_some_and(|set| set.is_match(relative))
+        })
+        .map(|entry| entry.into_path())
+        .collect();
+    // WalkDir yields in filesystem order; sort so a seed picks the same image everywhere.
+    images.sort();
+    Ok(images)
+}
+
+// Keeps the first path (in sorted order) of each set of files with identical
+// contents, so a copy under another name is not picked twice as often. The
+// dropped copies are returned keyed by the path that was kept.
+fn dedup_images(images: &mut Vec<PathBuf>) -> BTreeMap<PathBuf, Vec<PathBuf>> {
+    let mut seen: std::collections::HashMap<blake3::Hash, PathBuf> =
+        std::collections::HashMap::new();
+    let mut duplicates: BTreeMap<PathBuf, Vec<PathBuf>> = BTreeMap::new();
+    let mut unique = Vec::with_capacity(images.len());
+    for image in images.drain(..) {
+        let hash = fs::File::open(&image).and_then(|file| {
+            blake3::Hasher::new()
+                .update_reader(file)
+                .map(|h| h.finalize())
+        });
+        // Unreadable files are kept; rendering reports the real problem.
+        if let Ok(hash) = hash {
+            if let Some(first) = seen.get(&hash) {
+                info!(
+                    "dedup: skipping {} (same as {})",
+                    image.display(),
+                    first.display()
+                );
+                duplicates.entry(first.clone()).or_default().push(image);
+                continue;
+            }
+            seen.insert(hash, image.clone());
+        }
+        unique.push(image);
+    }
+    *images = unique;
+    duplicates
+}
+
+fn build_globset(patterns: &[String]) -> Result<Option<GlobSet>> {
+    if patterns.is_empty() {
+        return Ok(None);
+    }
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let glob = GlobBuilder::new(pattern)
+            .literal_separator(true)
+            .build()
+            .with_context(|| format!("invalid glob {pattern:?}"))?;
+        builder.add(glob);
+    }
+    Ok(Some(builder.build()?))
+}
+
+fn is_supported_image(path: &Path, extra_exts: &[String]) -> bool {
+    let Some(ext) = path.extension().and_then(OsStr::to_str) else {
+        return false;
+    };
+    let ext = ext.to_lowercase();
+    matches!(
+        ext.as_str(),
+        "png" | "jpg" | "jpeg" | "gif" | "webp" | "bmp" | "tif" | "tiff"
+    ) || extra_exts
+        .iter()
+        .any(|extra| extra.trim_start_matches('.').to_lowercase() == ext)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Severity {
+    Warning,
+    Error,
+}
+
+#[derive(Debug)]
+struct PackProblem {
+    severity: Severity,
+    message: String,
+}
+
+impl PackProblem {
+    fn warning(message: String) -> Self {
+        Self {
+            severity: Severity::Warning,
+            message,
+        }
+    }
+
+    fn error(message: String) -> Self {
+        Self {
+            severity: Severity::Error,
+            message,
+        }
+    }
+}
+
+fn validate_pack_dir(pack_root: &Path, config: &Config) -> Vec<PackProblem> {
+    let mut problems = Vec::new();
+
+    let meta_path = pack_root.join("pack.toml");
+    if !meta_path.is_file() {
+        problems.push(PackProblem::error(format!(
+            "missing {}",
+            meta_path.display()
+        )));
+        return problems;
+    }
+    let meta = match read_pack_meta(&meta_path) {
+        Ok(meta) => meta,
+        Err(err) => {
+            problems.push(PackProblem::error(format!("{err:#}")));
+            return problems;
+        }
+    };
+    if is_reserved_pack_name(&meta.name) {
+        problems.push(PackProblem::error(format!(
+            "name {:?} is reserved: `--pack {}` would never select this pack",
+            meta.name, meta.name
+        )));
+    }
+
+    let images_dir = pack_root.join(&meta.images_dir);
+    if !images_dir.is_dir() {
+        problems.push(PackProblem::error(format!(
+            "images_dir {} does not exist",
+            images_dir.display()
+        )));
+    } else {
+        for entry in WalkDir::new(&images_dir)
+            .follow_links(config.follow_symlinks)
+            .into_iter()
+            .filter_map(Result::ok)
+            .filter(|entry| entry.file_type().is_file())
+        {
+            if !is_supported_image(entry.path(), &config.extra_image_exts) {
+                problems.push(PackProblem::warning(format!(
+                    "unsupported image extension: {}",
+                    entry.path().display()
+                )));
+            }
+        }
+
+        let images = match collect_images(
+            pack_root,
+            &meta,
+            &config.extra_image_exts,
+            config.follow_symlinks,
+        ) {
+            Ok(images) => images,
+            Err(err) => {
+                problems.push(PackProblem::error(format!("{err:#}")));
+                Vec::new()
+            }
+        };
+        if images.is_empty() {
+            problems.push(PackProblem::error(format!(
+                "no supported images in {}",
+                images_dir.display()
+            )));
+        }
+        for image in &images {
+            if let Err(err) = fs::File::open(image) {
+                problems.push(PackProblem::error(format!(
+                    "unreadable image {}: {err}",
+                    image.display()
+                )));
+            }
+        }
+    }
+
+    if pack_root.join("messages.txt").exists() && read_messages(pack_root).is_empty() {
+        problems.push(PackProblem::warning(
+            "messages.txt has no non-empty lines".to_string(),
+        ));
+    }
+
+    problems
+}
+
+fn create_pack(dir: &Path, meta: &PackMeta, force: bool) -> Result<()> {
+    let has_entries = fs::read_dir(dir).is_ok_and(|mut entries| entries.next().is_some());
+    if has_entries && !force {
+        return Err(anyhow!(
+            "{} is not empty (use --force to write into it anyway)",
+            dir.display()
+        ));
+    }
+    fs::create_dir_all(dir.join(&meta.images_dir))
+        .with_context(|| format!("creating {}", dir.display()))?;
+
+    let quote = |value: &str| toml::Value::String(value.to_string()).to_string();
+    let pack_toml = format!(
+        "name = {}\nversion = {}\nlicense = {}\ndescription = {}\nimages_dir = {}\n\
+         # speaker = \"Tux\"\n\
+         # attribution_url = \"https://example.com/artist\"\n\
+         # include = [\"**/*.png\"]\n\
+         # exclude = [\"**/wip_*.png\"]\n",
+        quote(&meta.name),
+        quote(&meta.version),
+        quote(&meta.license),
+        quote(&meta.description),
+        quote(&meta.images_dir),
+    );
+    fs::write(dir.join("pack.toml"), pack_toml).context("writing pack.toml")?;
+    fs::write(
+        dir.join("messages.txt"),
+        format!("Hello from {}!\n", meta.name),
+    )
+    .context("writing messages.txt")?;
+    Ok(())
+}
+
+fn run_validate(pack_root: &Path, config: &Config) -> Result<()> {
+    let problems = validate_pack_dir(pack_root, config);
+    for problem in &problems {
+        let label = match problem.severity {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        println!("{label}: {}", problem.message);
+    }
+
+    let errors = problems
+        .iter()
+        .filter(|problem| problem.severity == Severity::Error)
+        .count();
+    if errors > 0 {
+        return Err(anyhow!(
+            "pack {} failed validation with {errors} error(s)",
+            pack_root.display()
+        ));
+    }
+    println!("pack {} is valid", pack_root.display());
+    Ok(())
+}
+
+fn is_gif(path: &Path) -> bool {
+    path.extension()
+        .and_then(OsStr::to_str)
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("gif"))
+}
+
+// Extracted frames are cache entries like renders, so they are evicted and
+// wiped with them. They are named after the source path, mtime and frame so
+// an edited GIF gets extracted again.
+fn gif_frame_path(image: &Path, frame: usize, cache_dir: &Path) -> PathBuf {
+    let mtime = path_mtime(image).unwrap_or(0);
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(image.to_string_lossy().as_bytes());
+    hasher.update(&mtime.to_le_bytes());
+    hasher.update(&frame.to_le_bytes());
+    cache_dir.join(format!("{}.{FRAME_FILE_EXT}", hasher.finalize().to_hex()))
+}
+
+fn extract_gif_frame_to_cache(image: &Path, frame: usize, cache_dir: &Path) -> Result<PathBuf> {
+    let path = gif_frame_path(image, frame, cache_dir);
+    if !path.exists() {
+        fs::create_dir_all(cache_dir)?;
+        let png = extract_gif_frame(image, frame)
+            .with_context(|| format!("extracting frame {frame} of {}", image.display()))?;
+        write_file_atomic(&path, &png)?;
+    }
+    Ok(path)
+}
+
+/// Composites the GIF up to `frame` (0-based) and encodes that frame as PNG.
+fn extract_gif_frame(image: &Path, frame: usize) -> Result<Vec<u8>> {
+    let mut options = gif::DecodeOptions::new();
+    options.set_color_output(gif::ColorOutput::RGBA);
+    let mut decoder = options.read_info(fs::File::open(image)?)?;
+    let (width, height) = (decoder.width() as usize, decoder.height() as usize);
+    let mut canvas = vec![0u8; width * height * 4];
+    let mut index = 0;
+    loop {
+        let Some(current) = decoder.read_next_frame()? else {
+            return Err(anyhow!("the image only has {index} frames"));
+        };
+        let previous = (current.dispose == gif::DisposalMethod::Previous).then(|| canvas.clone());
+        let (left, top) = (current.left as usize, current.top as usize);
+        let (frame_width, frame_height) = (current.width as usize, current.height as usize);
+        let rect = (0..frame_height)
+            .filter(|y| top + y < height)
+            .flat_map(|y| {
+                (0..frame_width)
+                    .filter(move |x| left + x < width)
+                    .map(move |x| (x, y))
+            });
+        for (x, y) in rect.clone() {
+            let src = (y * frame_width + x) * 4;
+            if current.buffer[src + 3] != 0 {
+                let dst = ((top + y) * width + left + x) * 4;
+                canvas[dst..dst + 4].copy_from_slice(&current.buffer[src..src + 4]);
+            }
+        }
+        if index == frame {
+            break;
+        }
+        match current.dispose {
+            gif::DisposalMethod::Background => {
+                for (x, y) in rect {
+                    let dst = ((top + y) * width + left + x) * 4;
+                    canvas[dst..dst + 4].fill(0);
+                }
+            }
+            gif::DisposalMethod::Previous => {
+                if let Some(previous) = previous {
+                    canvas = previous;
+                }
+            }
+            _ => {}
+        }
+        index += 1;
+    }
+
+    let mut png = Vec::new();
+    let mut encoder = png::Encoder::new(&mut png, width as u32, height as u32);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.write_header()?.write_image_data(&canvas)?;
+    Ok(png)
+}
+
+/// Returns each frame's delay in hundredths of a second, without decoding
+/// any pixels.
+fn gif_frame_delays(image: &Path) -> Result<Vec<u16>> {
+    let mut options = gif::DecodeOptions::new();
+    options.skip_frame_decoding(true);
+    let mut decoder = options.read_info(fs::File::open(image)?)?;
+    let mut delays = Vec::new();
+    while let Some(frame) = decoder.read_next_frame()? {
+        delays.push(frame.delay);
+    }
+    Ok(delays)
+}
+
+// chafa has no loop count, only a play time, so `loops` becomes the length
+// of that many passes. Only GIFs have timings we can read; other animations
+// play once.
+fn loops_duration_ms(image: &Path, fps: u32, loops: u32) -> Option<u64> {
+    if loops <= 1 || !is_gif(image) {
+        return None;
+    }
+    let delays = gif_frame_delays(image).ok()?;
+    let pass_ms = if fps > 0 {
+        delays.len() as u64 * 1000 / u64::from(fps)
+    } else {
+        // Like browsers, treat a zero delay as 100 ms.
+        delays
+            .iter()
+            .map(|&delay| {
+                if delay == 0 {
+                    100
+                } else {
+                    u64::from(delay) * 10
+                }
+            })
+            .sum()
+    };
+    (pass_ms > 0).then(|| pass_ms * u64::from(loops))
+}
+
+fn is_animated_image(path: &Path) -> bool {
+    path.extension()
+        .and_then(OsStr::to_str)
+        .is_some_and(|ext| matches!(ext.to_lowercase().as_str(), "gif" | "webp"))
+}
+
+fn read_messages(pack_root: &Path) -> BTreeMap<String, Vec<String>> {
+    let path = pack_root.join("messages.txt");
+    if !path.exists() {
+        return BTreeMap::new();
+    }
+    match fs::read_to_string(path) {
+        Ok(contents) => parse_messages(&contents),
+        Err(_) => BTreeMap::new(),
+    }
+}
+
+fn parse_messages(contents: &str) -> BTreeMap<String, Vec<String>> {
+    let mut messages: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    let mut category = DEFAULT_CATEGORY.to_string();
+    for line in contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+    {
+        // Only `[name]` starts a section; other bracketed lines such as
+        // "[citation needed]" stay messages.
+        if let Some(header) = line
+            .strip_prefix('[')
+            .and_then(|rest| rest.strip_suffix(']'))
+            .filter(|header| {
+                !header.is_empty()
+                    && header
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            })
+        {
+            category = header.to_string();
+            continue;
+        }
+        messages
+            .entry(category.clone())
+            .or_default()
+            .push(expand_line_breaks(line));
+    }
+    messages
+}
+
+// A literal `\n` starts a new line, since neither a `messages.txt` line nor a
+// plain shell argument can hold a real line break.
+fn expand_line_breaks(text: &str) -> String {
+    text.replace("\\n", "\n")
+}
+
+// Merges every pack into one pool so `--pack all` picks images and messages
+// across the whole collection. The blocklist is applied to each pack here,
+// while their roots are still known.
+fn pool_packs(packs: &[Pack], blocklist: Option<&GlobSet>) -> Pack {
+    let mut messages: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    let packs: Vec<&Pack> = packs.iter().filter(|p| p.meta.enabled).collect();
+    for pack in &packs {
+        for (category, lines) in &pack.messages {
+            messages
+                .entry(category.clone())
+                .or_default()
+                .extend(lines.iter().cloned());
+        }
+    }
+    Pack {
+        meta: PackMeta {
+            name: ALL_PACKS.to_string(),
+            version: String::new(),
+            license: String::new(),
+            description: "All installed packs".to_string(),
+            images_dir: String::new(),
+            include: Vec::new(),
+            exclude: Vec::new(),
+            speaker: None,
+            attribution_url: None,
+            enabled: true,
+            frames: BTreeMap::new(),
+            format: None,
+            colors: None,
+        },
+        root: PathBuf::new(),
+        images: packs
+            .iter()
+            .flat_map(|p| unblocked_images(p, blocklist))
+            .collect(),
+        duplicates: packs.iter().flat_map(|p| p.duplicates.clone()).collect(),
+        messages,
+    }
+}
+
+fn resolve_pack<'a>(
+    name: &str,
+    packs: &'a [Pack],
+    selection: PackSelection,
+    seed: Option<u64>,
+    blocklist: Option<&GlobSet>,
+) -> Result<Option<&'a Pack>> {
+    if name.is_empty() || name == RANDOM_PACK {
+        // Disabled packs are only used when asked for by name.
+        let candidates: Vec<&Pack> = packs.iter().filter(|p| p.meta.enabled).collect();
+        if candidates.is_empty() {
+            return Ok(None);
+        }
+        let idx = match selection {
+            PackSelection::Uniform => pick_index(candidates.len(), seed)?,
+            PackSelection::Weighted => {
+                // Weighted by the images that can actually be picked.
+                let weights = WeightedIndex::new(
+                    candidates
+                        .iter()
+                        .map(|p| unblocked_images(p, blocklist).len()),
+                )
+                .context("weighting packs by image count")?;
+                weights.sample(&mut seeded_rng(seed))
+            }
+        };
+        info!(
+            "picked pack {} from {} enabled packs ({selection:?} selection)",
+            candidates[idx].meta.name,
+            candidates.len()
+        );
+        return Ok(Some(candidates[idx]));
+    }
+    Ok(packs.iter().find(|p| p.meta.name == name))
+}
+
+fn resolve_message(
+    cli: &Cli,
+    pack: Option<&Pack>,
+    default_message: &str,
+    seed: Option<u64>,
+) -> Result<String> {
+    if let Some(text) = &cli.text {
+        return Ok(expand_line_breaks(text));
+    }
+
+    if let Some(path) = &cli.text_file {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("reading message file {}", path.display()))?;
+        return Ok(contents.trim_end_matches(['\n', '\r']).to_string());
+    }
+
+    if cli.image.as_deref() == Some(Path::new("-")) {
+        if cli.stdin {
+            return Err(anyhow!("--stdin cannot be used with --image -"));
+        }
+    } else if let Some(text) = read_stdin_text(cli.stdin)? {
+        return Ok(text);
+    }
+
+    if let Some(pack) = pack {
+        let category = cli.category.as_deref().unwrap_or(DEFAULT_CATEGORY);
+        match pack.messages.get(category) {
+            Some(messages) => {
+                let idx = pick_index(messages.len(), seed)?;
+                return Ok(messages[idx].clone());
+            }
+            None if cli.category.is_some() => {
+                let available: Vec<&str> = pack.messages.keys().map(String::as_str).collect();
+                return Err(anyhow!(
+                    "category not found in pack {}: {category} (available: {})",
+                    pack.meta.name,
+                    available.join(", ")
+                ));
+            }
+            None => {}
+        }
+    }
+
+    Ok(default_message.to_string())
+}
+
+// Counts user-perceived characters, so an emoji made of several code points
+// is kept or dropped as a whole. 0 means no limit.
+fn truncate_message(message: String, max_chars: usize) -> String {
+    if max_chars == 0 || message.graphemes(true).nth(max_chars).is_none() {
+        return message;
+    }
+    let mut truncated: String = message.graphemes(true).take(max_chars - 1).collect();
+    truncated.push('…');
+    truncated
+}
+
+// `--image -` streams the image itself through stdin.
+fn image_source(image: &Path) -> Result<ImageSource> {
+    if image != Path::new("-") {
+        return Ok(ImageSource::Path(image.to_path_buf()));
+    }
+    let mut bytes = Vec::new();
+    std::io::stdin()
+        .read_to_end(&mut bytes)
+        .context("reading image from stdin")?;
+    if bytes.is_empty() {
+        return Err(anyhow!("no image data on stdin"));
+    }
+    Ok(ImageSource::Bytes(bytes.into()))
+}
+
+fn read_stdin_text(force: bool) -> Result<Option<String>> {
+    if !force && std::io::stdin().is_terminal() {
+        return Ok(None);
+    }
+    let mut buffer = String::new();
+    std::io::stdin()
+        .read_to_string(&mut buffer)
+        .context("reading message from stdin")?;
+    let trimmed = buffer.trim_end();
+    if trimmed.trim_start().is_empty() {
+        Ok(None)
+    } else {
+        Ok(Some(trimmed.to_string()))
+    }
+}
+
+fn resolve_image(
+    cli: &Cli,
+    pack: Option<&Pack>,
+    pack_name: &str,
+    seed: Option<u64>,
+    recent: &[PathBuf],
+    blocklist: Option<&GlobSet>,
+) -> Result<PathBuf> {
+    if let Some(path) = &cli.image {
+        return Ok(path.clone());
+    }
+    let pack = pack.ok_or_else(|| anyhow!("pack not found: {pack_name}"))?;
+    if let Some(name) = &cli.image_name {
+        return find_image_by_name(pack, name);
+    }
+    pick_image(&unblocked_images(pack, blocklist), recent, seed).cloned()
+}
+
+// Drops images matched by the user's `image_blocklist`, by file name or by
+// path inside the pack. A blocked image is replaced by its first allowed
+// duplicate, if any. A pack that would be left empty keeps all of its images
+// instead.
+fn unblocked_images(pack: &Pack, blocklist: Option<&GlobSet>) -> Vec<PathBuf> {
+    let Some(blocklist) = blocklist else {
+        return pack.images.clone();
+    };
+    // The pooled `all` pack has no root; `pool_packs` already filtered it.
+    if pack.root.as_os_str().is_empty() {
+        return pack.images.clone();
+    }
+    let allowed_path = |image: &&PathBuf| {
+        let relative = image.strip_prefix(&pack.root).unwrap_or(image);
+        !blocklist.is_match(relative)
+            && !image
+                .file_name()
+                .is_some_and(|name| blocklist.is_match(Path::new(name)))
+    };
+    let allowed: Vec<PathBuf> = pack
+        .images
+        .iter()
+        .filter_map(|image| {
+            std::iter::once(image)
+                .chain(pack.duplicates.get(image).into_iter().flatten())
+                .find(allowed_path)
+        })
+        .cloned()
+        .collect();
+    if allowed.is_empty() && !pack.images.is_empty() {
+        eprintln!(
+            "warning: image_blocklist matches every image in pack {}; ignoring it",
+            pack.meta.name
+        );
+        return pack.images.clone();
+    }
+    allowed
+}
+
+fn pick_image<'a>(
+    images: &'a [PathBuf],
+    recent: &[PathBuf],
+    seed: Option<u64>,
+) -> Result<&'a PathBuf> {
+    let mut candidates: Vec<&PathBuf> = Vec::new();
+    if images.len() > recent.len() {
+        candidates = images.iter().filter(|img| !recent.contains(img)).collect();
+    }
+    if candidates.is_empty() {
+        candidates = images.iter().collect();
+    }
+    let idx = pick_index(candidates.len(), seed)?;
+    info!(
+        "image: #{idx} of {} candidates ({} skipped as recent): {}",
+        candidates.len(),
+        images.len() - candidates.len(),
+        candidates[idx].display()
+    );
+    Ok(candidates[idx])
+}
+
+fn history_path() -> Option<PathBuf> {
+    ProjectDirs::from("", "", "leftysay").map(|proj| proj.data_dir().join(HISTORY_FILE))
+}
+
+fn read_history() -> Vec<PathBuf> {
+    history_path()
+        .and_then(|path| fs::read_to_string(path).ok())
+        .and_then(|contents| serde_json::from_str(&contents).ok())
+        .unwrap_or_default()
+}
+
+/// Picks `count` distinct images, avoiding recent ones while enough others remain.
+fn pick_images(
+    images: &[PathBuf],
+    count: usize,
+    recent: &[PathBuf],
+    seed: Option<u64>,
+) -> Result<Vec<PathBuf>> {
+    if images.is_empty() {
+        return Err(anyhow!("no images available"));
+    }
+    let fresh: Vec<&PathBuf> = images.iter().filter(|img| !recent.contains(img)).collect();
+    let mut candidates = if fresh.len() >= count {
+        fresh
+    } else {
+        images.iter().collect()
+    };
+    candidates.shuffle(&mut seeded_rng(seed));
+    candidates.truncate(count);
+    Ok(candidates.into_iter().cloned().collect())
+}
+
+fn record_history(mut recent: Vec<PathBuf>, images: &[PathBuf], size: usize) -> Result<()> {
+    let path = history_path().ok_or_else(|| anyhow!("no data directory"))?;
+    recent.retain(|p| !images.contains(p));
+    recent.extend(images.iter().cloned());
+    let excess = recent.len().saturating_sub(size);
+    recent.drain(..excess);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, serde_json::to_string(&recent)?)?;
+    Ok(())
+}
+
+fn remote_image_url(path: &Path) -> Option<&str> {
+    path.to_str()
+        .filter(|s| s.starts_with("http://") || s.starts_with("https://"))
+}
+
+#[derive(Default, Deserialize, Serialize)]
+struct RemoteImageMeta {
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+// Where a download and its validators are kept, keyed by URL.
+fn remote_image_paths(url: &str, cache_dir: &Path) -> (PathBuf, PathBuf) {
+    let dir = cache_dir.join(REMOTE_IMAGE_DIR);
+    let name = blake3::hash(url.as_bytes()).to_hex().to_string();
+    let ext = Path::new(url.split(['?', '#']).next().unwrap_or(url))
+        .extension()
+        .and_then(OsStr::to_str)
+        .filter(|ext| ext.chars().all(|c| c.is_ascii_alphanumeric()))
+        .map(|ext| format!(".{ext}"))
+        .unwrap_or_default();
+    (
+        dir.join(format!("{name}{ext}")),
+        dir.join(format!("{name}.json")),
+    )
+}
+
+// Downloads into the cache dir, keyed by URL. The file is only rewritten when
+// the server sends new content, so its mtime (part of `cache_key`) tracks the
+// ETag/Last-Modified validators. A stale copy is used if the fetch fails.
+fn fetch_remote_image(url: &str, cache_dir: &Path) -> Result<PathBuf> {
+    let (image_path, meta_path) = remote_image_paths(url, cache_dir);
+    let dir = cache_dir.join(REMOTE_IMAGE_DIR);
+    let meta: RemoteImageMeta = fs::read_to_string(&meta_path)
+        .ok()
+        .and_then(|contents| serde_json::from_str(&contents).ok())
+        .filter(|_| image_path.is_file())
+        .unwrap_or_default();
+
+    let agent = ureq::AgentBuilder::new()
+        .timeout(REMOTE_IMAGE_TIMEOUT)
+        .build();
+    let mut request = agent.get(url);
+    if let Some(etag) = &meta.etag {
+        request = request.set("If-None-Match", etag);
+    }
+    if let Some(last_modified) = &meta.last_modified {
+        request = request.set("If-Modified-Since", last_modified);
+    }
+    let response = match request.call() {
+        Ok(response) => response,
+        Err(_) if image_path.is_file() => return Ok(image_path),
+        Err(e) => return Err(anyhow!("downloading {url}: {e}")),
+    };
+    if response.status() == 304 {
+        return Ok(image_path);
+    }
+
+    let fresh = RemoteImageMeta {
+        etag: response.header("ETag").map(str::to_string),
+        last_modified: response.header("Last-Modified").map(str::to_string),
+    };
+    let mut bytes = Vec::new();
+    response
+        .into_reader()
+        .take(REMOTE_IMAGE_MAX_BYTES + 1)
+        .read_to_end(&mut bytes)
+        .with_context(|| format!("downloading {url}"))?;
+    if bytes.len() as u64 > REMOTE_IMAGE_MAX_BYTES {
+        return Err(anyhow!(
+            "downloading {url}: image is larger than {}",
+            format_size(REMOTE_IMAGE_MAX_BYTES)
+        ));
+    }
+    fs::create_dir_all(&dir)?;
+    write_file_atomic(&image_path, &bytes)?;
+    write_file_atomic(&meta_path, serde_json::to_string(&fresh)?.as_bytes())?;
+    Ok(image_path)
+}
+
+fn find_image_by_name(pack: &Pack, name: &str) -> Result<PathBuf> {
+    let wanted = name.to_lowercase();
+    let file_names = || {
+        pack.images.iter().filter_map(|image| {
+            image
+                .file_name()
+                .and_then(OsStr::to_str)
+                .map(|n| (image, n))
+        })
+    };
+    if let Some((image, _)) = file_names().find(|(_, n)| n.to_lowercase() == wanted) {
+        return Ok(image.clone());
+    }
+    let available: Vec<&str> = file_names().map(|(_, n)| n).collect();
+    Err(anyhow!(
+        "image not found in pack {}: {name} (available: {})",
+        pack.meta.name,
+        available.join(", ")
+    ))
+}
+
+#[derive(Clone, Copy, Debug, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum SeedSource {
+    Flag,
+    Env,
+    Daily,
+    Entropy,
+}
+
+impl SeedSource {
+    fn describe(self) -> &'static str {
+        match self {
+            SeedSource::Flag => "--seed",
+            SeedSource::Env => "LEFTYSAY_SEED",
+            SeedSource::Daily => "--daily",
+            SeedSource::Entropy => "entropy",
+        }
+    }
+}
+
+fn resolve_seed(flag: Option<u64>, daily: bool) -> (Option<u64>, SeedSource) {
+    if let Some(seed) = flag {
+        return (Some(seed), SeedSource::Flag);
+    }
+    let env_seed = std::env::var("LEFTYSAY_SEED")
+        .ok()
+        .and_then(|value| value.trim().parse::<u64>().ok());
+    match env_seed {
+        Some(seed) => (Some(seed), SeedSource::Env),
+        None if daily => (Some(daily_seed()), SeedSource::Daily),
+        None => (None, SeedSource::Entropy),
+    }
+}
+
+const GREETINGS: &[(&str, [&str; 3])] = &[
+    ("en", ["Good morning!", "Good afternoon!", "Good evening!"]),
+    ("de", ["Guten Morgen!", "Guten Tag!", "Guten Abend!"]),
+    ("el", ["Καλημέρα!", "Καλό απόγευμα!", "Καλησπέρα!"]),
+    (
+        "es",
+        ["¡Buenos días!", "¡Buenas tardes!", "¡Buenas noches!"],
+    ),
+    ("fr", ["Bonjour !", "Bon après-midi !", "Bonsoir !"]),
+    ("it", ["Buongiorno!", "Buon pomeriggio!", "Buonasera!"]),
+    ("nl", ["Goedemorgen!", "Goedemiddag!", "Goedenavond!"]),
+    ("pt", ["Bom dia!", "Boa tarde!", "Boa noite!"]),
+];
+
+/// First non-empty of `LC_ALL`, `LC_TIME` and `LANG`, as POSIX resolves them.
+fn system_locale() -> String {
+    ["LC_ALL", "LC_TIME", "LANG"]
+        .iter()
+        .filter_map(|key| std::env::var(key).ok())
+        .find(|value| !value.is_empty())
+        .unwrap_or_default()
+}
+
+/// Greeting for `hour` (0-23) in the language of `locale` (e.g. `de_DE.UTF-8`),
+/// falling back to English.
+fn greeting_for(locale: &str, hour: u32) -> &'static str {
+    let language = locale
+        .split(['_', '.', '@', '-'])
+        .next()
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    let words = GREETINGS
+        .iter()
+        .find(|(code, _)| *code == language)
+        .unwrap_or(&GREETINGS[0])
+        .1;
+    match hour {
+        0..=11 => words[0],
+        12..=17 => words[1],
+        _ => words[2],
+    }
+}
+
+fn daily_seed() -> u64 {
+    let today = chrono::Local::now().date_naive().to_string();
+    let hash = blake3::hash(today.as_bytes());
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&hash.as_bytes()[..8]);
+    u64::from_le_bytes(bytes)
+}
+
+fn pick_index(len: usize, seed: Option<u64>) -> Result<usize> {
+    if len == 0 {
+        return Err(anyhow!("no images available"));
+    }
+    Ok(seeded_rng(seed).gen_range(0..len))
+}
+
+fn seeded_rng(seed: Option<u64>) -> StdRng {
+    match seed {
+        Some(seed) => SeedableRng::seed_from_u64(seed),
+        None => SeedableRng::from_entropy(),
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct BubbleOptions<'a> {
+    max_width: usize,
+    padding: usize,
+    speaker: Option<&'a str>,
+    glyphs: BubbleGlyphs,
+    rainbow: Option<f64>,
+    ascii: bool,
+    max_lines: usize,
+    tail_up: bool,
+    tail: TailPosition,
+    rtl: bool,
+    long_word: LongWord,
+}
+
+impl Default for BubbleOptions<'_> {
+    fn default() -> Self {
+        Self {
+            max_width: DEFAULT_BUBBLE_MAX_WIDTH,
+            padding: DEFAULT_BUBBLE_PADDING,
+            speaker: None,
+            glyphs: CLASSIC_GLYPHS,
+            rainbow: None,
+            ascii: false,
+            max_lines: 0,
+            tail_up: false,
+            tail: TailPosition::Right,
+            rtl: false,
+            long_word: LongWord::Break,
+        }
+    }
+}
+
+fn render_bubble(text: &str, term_cols: usize, options: &BubbleOptions) -> Vec<String> {
+    if options.ascii {
+        let speaker = options.speaker.map(to_ascii);
+        let ascii_options = BubbleOptions {
+            speaker: speaker.as_deref(),
+            glyphs: CLASSIC_GLYPHS,
+            ascii: false,
+            ..*options
+        };
+        return render_bubble(&to_ascii(text), term_cols, &ascii_options);
+    }
+    let padding = 2 + 2 * options.padding;
+    if term_cols <= padding + 10 {
+        return vec![text.to_string()];
+    }
+
+    let available = term_cols.saturating_sub(padding);
+    let bubble_width = match options.max_width {
+        0 => available,
+        max_width => min(available, max_width),
+    };
+    let mut wrapped = wrap_text_lines(text, bubble_width, options.long_word);
+
+    if wrapped.is_empty() {
+        return Vec::new();
+    }
+    if options.max_lines > 0 && wrapped.len() > options.max_lines {
+        wrapped.truncate(options.max_lines);
+        let last = wrapped.last_mut().expect("max_lines is non-zero");
+        while !last.is_empty() && visible_width(last) + 3 > bubble_width {
+            last.pop();
+        }
+        last.push_str("...");
+    }
+
+    let max_line_len = wrapped
+        .iter()
+        .map(|line| visible_width(line))
+        .max()
+        .unwrap_or(0);
+    let inner_width = max_line_len + 2 * options.padding;
+    let space = " ".repeat(options.padding);
+    let glyphs = &options.glyphs;
+    let body = |row: usize, line: &str| {
+        let padded = if options.rtl {
+            let indent = max_line_len.saturating_sub(visible_width(line));
+            format!("{}{line}", " ".repeat(indent))
+        } else {
+            pad_line(line, max_line_len)
+        };
+        match options.rainbow {
+            Some(phase) => rainbow_line(&padded, row, phase),
+            None => padded,
+        }
+    };
+    let mut lines = Vec::new();
+    lines.push(format!(" {}", glyphs.top.to_string().repeat(inner_width)));
+    if wrapped.len() == 1 {
+        lines.push(format!(
+            "{}{space}{}{space}{}",
+            glyphs.single_left,
+            body(0, &wrapped[0]),
+            glyphs.single_right
+        ));
+    } else {
+        for (idx, line) in wrapped.iter().enumerate() {
+            let (left, right) = match idx {
+                0 => (glyphs.top_left, glyphs.top_right),
+                i if i + 1 == wrapped.len() => (glyphs.bottom_left, glyphs.bottom_right),
+                _ => (glyphs.left, glyphs.right),
+            };
+            lines.push(format!("{left}{space}{}{space}{right}", body(idx, line)));
+        }
+    }
+    lines.push(format!(
+        " {}",
+        glyphs.bottom.to_string().repeat(inner_width)
+    ));
+    if let Some(speaker) = options.speaker.map(str::trim).filter(|s| !s.is_empty()) {
+        let label = format!("-- {speaker}");
+        let indent = (inner_width + 2).saturating_sub(UnicodeWidthStr::width(label.as_str()));
+        lines.push(format!("{}{label}", " ".repeat(indent)));
+    }
+
+    // Right-to-left text is read from the right edge, so the tail leaves
+    // from that side too.
+    let tail_position = match options.tail {
+        TailPosition::Left if options.rtl => TailPosition::Right,
+        position => position,
+    };
+    let mut tail = Vec::new();
+    append_tail(&mut tail, inner_width, term_cols, tail_position);
+    if options.tail_up {
+        // The bubble sits below the image, so the tail rises from its top border.
+        tail.reverse();
+        tail.append(&mut lines);
+        lines = tail;
+    } else {
+        lines.append(&mut tail);
+    }
+
+    lines
+}
+
+// The classic cowsay cow, drawn with its head at `head_col`.
+fn render_speaker(head_col: usize, eyes: &str, tongue: &str) -> Vec<String> {
+    let two_cols = |part: &str| {
+        let mut out = String::new();
+        for c in part.chars() {
+            if UnicodeWidthStr::width(out.as_str()) + UnicodeWidthChar::width(c).unwrap_or(0) > 2 {
+                break;
+            }
+            out.push(c);
+        }
+        pad_line(&out, 2)
+    };
+    let (eyes, tongue) = (two_cols(eyes), two_cols(tongue));
+    let indent = " ".repeat(head_col);
+    [
+        "^__^".to_string(),
+        format!("({eyes})\\_______"),
+        "(__)\\       )\\/\\".to_string(),
+        format!(" {tongue} ||----w |"),
+        "    ||     ||".to_string(),
+    ]
+    .into_iter()
+    .map(|line| format!("{indent}{line}").trim_end().to_string())
+    .collect()
+}
+
+fn to_ascii(text: &str) -> String {
+    text.chars()
+        .map(|c| if c.is_ascii() { c } else { '?' })
+        .collect()
+}
+
+fn rainbow_line(line: &str, row: usize, phase: f64) -> String {
+    const FREQ: f64 = 0.1;
+    let mut out = String::with_capacity(line.len() * 20);
+    for (col, c) in line.chars().enumerate() {
+        if c == ' ' {
+            out.push(c);
+            continue;
+        }
+        let t = FREQ * (col + 2 * row) as f64 + phase;
+        let channel = |offset: f64| ((t + offset).sin() * 127.0 + 128.0) as u8;
+        let (red, green, blue) = (channel(0.0), channel(2.094), channel(4.189));
+        out.push_str(&format!("\x1b[38;2;{red};{green};{blue}m{c}"));
+    }
+    out.push_str("\x1b[0m");
+    out
+}
+
+fn pad_line(line: &str, width: usize) -> String {
+    let mut s = line.to_string();
+    let line_width = visible_width(line);
+    if line_width < width {
+        s.push_str(&" ".repeat(width - line_width));
+    }
+    s
+}
+
+fn append_tail(
+    lines: &mut Vec<String>,
+    bubble_inner_width: usize,
+    term_cols: usize,
+    position: TailPosition,
+) {
+    let bubble_width = bubble_inner_width + 2;
+    let bubble_indent = 1usize;
+    let bubble_right = bubble_indent + bubble_width;
+    let mut start_col = match position {
+        TailPosition::None => return,
+        TailPosition::Left => bubble_indent + 1,
+        TailPosition::Center => bubble_indent + bubble_width / 2,
+        TailPosition::Right => bubble_right + 1,
+    };
+    if start_col + 1 >= term_cols {
+        start_col = bubble_indent + bubble_width.saturating_sub(1);
+    }
+
+    let tail = ["o", " o", "  o"];
+    for (i, segment) in tail.iter().enumerate() {
+        let spaces = start_col.saturating_add(i);
+        lines.push(format!("{:width$}{}", "", segment, width = spaces));
+    }
+}
+
+fn text_width(text: &str) -> usize {
+    text.lines().map(visible_width).max().unwrap_or(0)
+}
+
+// A bubble wider than the image would hide all of it.
+fn overlay_fits(bubble: &[String], image_output: &str) -> bool {
+    let image_width = text_width(image_output);
+    image_width > 0 && bubble.iter().all(|line| visible_width(line) <= image_width)
+}
+
+fn image_indent(image_output: &str, term_cols: usize) -> usize {
+    let width = text_width(image_output);
+    if width == 0 {
+        return 0;
+    }
+    term_cols.saturating_sub(width) / 2
+}
+
+fn indent_lines(text: &str, indent: usize) -> String {
+    let prefix = " ".repeat(indent);
+    let mut out = String::with_capacity(text.len() + indent * text.lines().count());
+    for line in text.split_inclusive('\n') {
+        if line != "\n" {
+            out.push_str(&prefix);
+        }
+        out.push_str(line);
+    }
+    out
+}
+
+fn center_bubble(bubble: &mut [String], image_output: &str, indent: usize, term_cols: usize) {
+    let image_width = image_output.lines().map(visible_width).max().unwrap_or(0);
+    let Some(tail_tip) = bubble
+        .last()
+        .map(|line| visible_width(line).saturating_sub(1))
+    else {
+        return;
+    };
+    if image_width == 0 {
+        return;
+    }
+
+    let bubble_width = bubble
+        .iter()
+        .map(|line| visible_width(line))
+        .max()
+        .unwrap_or(0);
+    let max_offset = term_cols.saturating_sub(bubble_width);
+    let offset = min(
+        (indent + image_width / 2).saturating_sub(tail_tip),
+        max_offset,
+    );
+    if offset == 0 {
+        return;
+    }
+    let prefix = " ".repeat(offset);
+    for line in bubble.iter_mut() {
+        line.insert_str(0, &prefix);
+    }
+}
+
+fn visible_width(line: &str) -> usize {
+    UnicodeWidthStr::width(strip_ansi(line).as_str())
+}
+
+/// Draws `overlay` over the first lines of `image`, replacing the cells it
+/// covers while keeping the colors of the rest of each image line.
+fn overlay_text(overlay: &[String], image: &str) -> String {
+    let width = overlay
+        .iter()
+        .map(|line| visible_width(line))
+        .max()
+        .unwrap_or(0);
+    let image_lines: Vec<&str> = image.lines().collect();
+    let mut out = String::with_capacity(image.len());
+    for row in 0..image_lines.len().max(overlay.len()) {
+        let line = image_lines.get(row).copied().unwrap_or("");
+        match overlay.get(row) {
+            Some(top) => out.push_str(&overlay_line(top, width, line)),
+            None => out.push_str(line),
+        }
+        out.push('\n');
+    }
+    out
+}
+
+fn overlay_line(top: &str, width: usize, line: &str) -> String {
+    // Skip the first `width` cells of `line`, keeping its escape sequences so
+    // the colors in effect after the overlay are unchanged.
+    let mut escapes = String::new();
+    let mut col = 0;
+    let mut chars = line.char_indices().peekable();
+    let mut rest = line.len();
+    while let Some(&(i, c)) = chars.peek() {
+        if c == '\x1b' {
+            let start = i;
+            chars.next();
+            if chars.next_if(|&(_, c)| c == '[').is_some() {
+                for (_, c) in chars.by_ref() {
+                    if ('@'..='~').contains(&c) {
+                        break;
+                    }
+                }
+            } else {
+                chars.next();
+            }
+            let end = chars.peek().map_or(line.len(), |&(i, _)| i);
+            escapes.push_str(&line[start..end]);
+            continue;
+        }
+        if col >= width {
+            rest = i;
+            break;
+        }
+        col += UnicodeWidthChar::width(c).unwrap_or(0);
+        chars.next();
+    }
+
+    let mut out = String::with_capacity(line.len() + top.len());
+    out.push_str("\x1b[0m");
+    out.push_str(top);
+    out.push_str(&" ".repeat(width - visible_width(top)));
+    out.push_str("\x1b[0m");
+    out.push_str(&escapes);
+    // A wide character cut by the overlay edge leaves blank cells behind.
+    out.push_str(&" ".repeat(col.saturating_sub(width)));
+    out.push_str(&line[rest..]);
+    out
+}
+
+fn strip_ansi(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\x1b' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('[') => {
+                for c in chars.by_ref() {
+                    if ('@'..='~').contains(&c) {
+                        break;
+                    }
+                }
+            }
+            // OSC, DCS (sixel), APC (kitty) and friends run until BEL or ST.
+            Some(']' | 'P' | '_' | '^' | 'X') => {
+                while let Some(c) = chars.next() {
+                    if c == '\x07' {
+                        break;
+                    }
+                    if c == '\x1b' && chars.peek() == Some(&'\\') {
+                        chars.next();
+                        break;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    out
+}
+
+// Cuts space-separated words wider than the bubble, such as long URLs, to
+// fit with "...". A cut word is returned as a piece of its own, so it gets a
+// line to itself rather than being wrapped at a "/" after the words before
+// it. Runs made only of CJK text have no spaces and are still wrapped between
+// characters, as is every long word in a bubble too narrow to keep a
+// character before the ellipsis.
+fn truncate_long_words(line: &str, width: usize) -> Vec<String> {
+    let mut pieces = Vec::new();
+    let mut current = String::with_capacity(line.len());
+    for word in line.split_inclusive(' ') {
+        let (word, space) = match word.strip_suffix(' ') {
+            Some(word) => (word, " "),
+            None => (word, ""),
+        };
+        let all_wide = strip_ansi(word)
+            .chars()
+            .all(|c| UnicodeWidthChar::width(c) == Some(2));
+        if visible_width(word) <= width || all_wide {
+            current.push_str(word);
+            current.push_str(space);
+            continue;
+        }
+        if !current.trim_end().is_empty() {
+            pieces.push(current.trim_end().to_string());
+        }
+        current.clear();
+        pieces.push(if width < 4 {
+            word.to_string()
+        } else {
+            cut_word(word, width)
+        });
+    }
+    if !current.trim_end().is_empty() {
+        pieces.push(current.trim_end().to_string());
+    }
+    pieces
+}
+
+// Keeps the start of `word` that fits in `width` columns with "...". Escape
+// sequences take no columns and are never split; a colored word is closed
+// with a reset so the color cannot run into the border.
+fn cut_word(word: &str, width: usize) -> String {
+    let budget = width - 3;
+    let mut cut = String::new();
+    let mut used = 0;
+    let mut rest = word;
+    while let Some(c) = rest.chars().next() {
+        let len = if c == '\x1b' {
+            ansi_escape_len(rest)
+        } else {
+            let cols = UnicodeWidthChar::width(c).unwrap_or(0);
+            if used + cols > budget {
+                break;
+            }
+            used += cols;
+            c.len_utf8()
+        };
+        cut.push_str(&rest[..len]);
+        rest = &rest[len..];
+    }
+    cut.push_str("...");
+    if word.contains('\x1b') {
+        cut.push_str("\x1b[0m");
+    }
+    cut
+}
+
+// Byte length of the escape sequence `text` starts with, using the same
+// rules as `strip_ansi`.
+fn ansi_escape_len(text: &str) -> usize {
+    let mut chars = text.char_indices().skip(1).peekable();
+    match chars.next() {
+        Some((_, '[')) => {
+            for (i, c) in chars {
+                if ('@'..='~').contains(&c) {
+                    return i + c.len_utf8();
+                }
+            }
+        }
+        Some((_, ']' | 'P' | '_' | '^' | 'X')) => {
+            while let Some((i, c)) = chars.next() {
+                if c == '\x07' {
+                    return i + 1;
+                }
+                if c == '\x1b' && chars.peek().is_some_and(|&(_, c)| c == '\\') {
+                    return i + 2;
+                }
+            }
+        }
+        Some((i, c)) => return i + c.len_utf8(),
+        None => return 1,
+    }
+    text.len()
+}
+
+fn wrap_text_lines(text: &str, width: usize, long_word: LongWord) -> Vec<String> {
+    // Break by display width at Unicode line-break opportunities so CJK text,
+    // which has no spaces, wraps at the right visual column.
+    let options = Options::new(width)
+        .word_separator(WordSeparator::UnicodeBreakProperties)
+        .word_splitter(WordSplitter::HyphenSplitter)
+        .break_words(true);
+    let mut lines = Vec::new();
+    let normalized = text.replace('\t', "    ");
+    for raw_line in normalized.lines() {
+        let trimmed = raw_line.trim_end();
+        if trimmed.is_empty() {
+            lines.push(String::new());
+            continue;
+        }
+        let pieces = if long_word == LongWord::Truncate {
+            truncate_long_words(trimmed, width)
+        } else {
+            vec![trimmed.to_string()]
+        };
+        for piece in &pieces {
+            for line in wrap(piece, &options) {
+                lines.push(line.into_owned());
+            }
+        }
+    }
+    if lines.is_empty() {
+        lines.push(String::new());
+    }
+    lines
+}
+
+/// Where chafa reads the image from: a file, or bytes fed to its stdin.
+enum ImageSource {
+    Path(PathBuf),
+    // Shared, so retrying chafa with a fallback format does not copy it.
+    Bytes(Arc<[u8]>),
+}
+
+impl ImageSource {
+    /// The image argument for chafa; `-` makes it read stdin.
+    fn chafa_path(&self) -> &Path {
+        match self {
+            ImageSource::Path(path) => path,
+            ImageSource::Bytes(_) => Path::new("-"),
+        }
+    }
+}
+
+struct RenderedImage {
+    text: String,
+    cache_hit: bool,
+    elapsed: Duration,
+}
+
+// Renders each image into an equal share of the width and lays them out
+// side by side. Graphics protocols cannot be placed next to each other, so
+// tiles always use symbols.
+fn montage_tile_options(options: RenderOptions, count: usize) -> RenderOptions {
+    let gaps = MONTAGE_GAP * (count - 1);
+    RenderOptions {
+        cols: (options.cols.saturating_sub(gaps) / count).max(1),
+        format: ChafaFormat::Unicode,
+        format_fallbacks: &[],
+        animate: false,
+        fps: 0,
+        duration_ms: None,
+        ..options
+    }
+}
+
+fn render_montage(
+    chafa: &Path,
+    images: &[PathBuf],
+    options: RenderOptions,
+) -> Result<RenderedImage> {
+    let options = montage_tile_options(options, images.len());
+    let mut tiles = Vec::with_capacity(images.len());
+    let (mut cache_hit, mut elapsed) = (true, Duration::ZERO);
+    for image in images {
+        let rendered = render_image(chafa, &ImageSource::Path(image.clone()), options)
+            .with_context(|| format!("rendering {}", image.display()))?;
+        cache_hit &= rendered.cache_hit;
+        elapsed += rendered.elapsed;
+        tiles.push(rendered.text);
+    }
+    Ok(RenderedImage {
+        text: compose_horizontal(&tiles, MONTAGE_GAP),
+        cache_hit,
+        elapsed,
+    })
+}
+
+/// Places blocks of (possibly colored) text next to each other, padding each
+/// to its widest visible line and resetting colors at every tile edge.
+fn compose_horizontal(blocks: &[String], gap: usize) -> String {
+    let blocks: Vec<Vec<&str>> = blocks.iter().map(|b| b.lines().collect()).collect();
+    let widths: Vec<usize> = blocks
+        .iter()
+        .map(|lines| {
+            lines
+                .iter()
+                .map(|line| visible_width(line))
+                .max()
+                .unwrap_or(0)
+        })
+        .collect();
+    let rows = blocks.iter().map(Vec::len).max().unwrap_or(0);
+    let mut out = String::new();
+    for row in 0..rows {
+        let mut line = String::new();
+        for (i, (lines, width)) in blocks.iter().zip(&widths).enumerate() {
+            if i > 0 {
+                line.push_str(&" ".repeat(gap));
+            }
+            let cell = lines.get(row).copied().unwrap_or("");
+            line.push_str(cell);
+            if cell.contains('\x1b') {
+                line.push_str("\x1b[0m");
+            }
+            line.push_str(&" ".repeat(width - visible_width(cell)));
+        }
+        out.push_str(line.trim_end_matches(' '));
+        out.push('\n');
+    }
+    out
+}
+
+fn render_image(
+    chafa: &Path,
+    image: &ImageSource,
+    options: RenderOptions,
+) -> Result<RenderedImage> {
+    let cache_dir = options.cache_dir;
+    let cache_path = cache_path(cache_dir, image, &options)?;
+
+    if options.cache_enabled {
+        ensure_cache_version(cache_dir)?;
+        // An empty entry is left over from a failed write; render it again.
+        if let Some(contents) = read_cache_file(&cache_path).filter(|c| !c.is_empty()) {
+            info!("cache hit: {}", cache_path.display());
+            // Plain entries were just read from their legacy path; store them
+            // compressed. Another process may be rewriting or evicting the
+            // entry, which is harmless.
+            if !cache_path.exists() {
+                let _ = write_cache_file(&cache_path, &contents);
+            }
+            let _ = touch_cache_entry(&cache_path);
+            return Ok(RenderedImage {
+                text: contents,
+                cache_hit: true,
+                elapsed: Duration::ZERO,
+            });
+        }
+    }
+
+    if options.cache_enabled {
+        info!("cache miss: {}", cache_path.display());
+    }
+    let started = Instant::now();
+    let (output, fell_back) = run_chafa(chafa, image, &options)?;
+    let elapsed = started.elapsed();
+
+    // A fallback's output would otherwise be served for the requested
+    // format from then on.
+    if fell_back {
+        info!("cache: not storing output from a fallback format");
+    }
+    if options.cache_enabled && !fell_back {
+        fs::create_dir_all(cache_dir)?;
+        write_cache_file(&cache_path, &output)?;
+        enforce_cache_limit(
+            cache_dir,
+            Some(&cache_path),
+            options.cache_max_mb * 1024 * 1024,
+            options.cache_max_entries,
+        )?;
+    }
+
+    Ok(RenderedImage {
+        text: output,
+        cache_hit: false,
+        elapsed,
+    })
+}
+
+// Tries the requested format first, then each format of the fallback chain
+// with auto colors pinned to truecolor. Without `format_fallbacks`, only an
+// auto format falls back, to symbols. Also returns whether the output came
+// from a fallback.
+fn run_chafa(chafa: &Path, image: &ImageSource, options: &RenderOptions) -> Result<(String, bool)> {
+    let default_chain = [match options.format {
+        ChafaFormat::Auto => ChafaFormat::Unicode,
+        format => format,
+    }];
+    let chain = if options.format_fallbacks.is_empty() {
+        &default_chain[..]
+    } else {
+        options.format_fallbacks
+    };
+    let colors = match options.colors {
+        ChafaColors::Auto => ChafaColors::Truecolor,
+        colors => colors,
+    };
+    let mut attempts = vec![*options];
+    for &format in chain {
+        if !attempts
+            .iter()
+            .any(|tried| tried.format == format && tried.colors == colors)
+        {
+            attempts.push(RenderOptions {
+                format,
+                colors,
+                ..*options
+            });
+        }
+    }
+
+    let mut errors = Vec::new();
+    for (i, attempt) in attempts.iter().enumerate() {
+        if i > 0 {
+            info!(
+                "retrying chafa with {:?}/{:?}",
+                attempt.format, attempt.colors
+            );
+        }
+        let err = match run_chafa_once(chafa, image, attempt) {
+            Ok(output) if output.status.success() => {
+                return Ok((String::from_utf8_lossy(&output.stdout).to_string(), i > 0));
+            }
+            Ok(output) => String::from_utf8_lossy(&output.stderr).to_string(),
+            Err(err) => err.to_string(),
+        };
+        info!(
+            "chafa failed with {:?}/{:?}",
+            attempt.format, attempt.colors
+        );
+        debug!("chafa stderr:\n{err}");
+        errors.push(format!(
+            "{}/{}: {}",
+            attempt.format.as_arg(),
+            attempt.colors.as_arg(),
+            err.trim_end()
+        ));
+    }
+
+    Err(anyhow!("chafa failed:\n{}", errors.join("\n")))
+}
+
+struct ProtocolDetection {
+    format: ChafaFormat,
+    signals: Vec<String>,
+}
+
+fn effective_format(format: ChafaFormat) -> ChafaFormat {
+    match format {
+        ChafaFormat::Auto => detect_protocol(|name| std::env::var(name).ok()).format,
+        format => format,
+    }
+}
+
+// An explicit `bg` wins over the theme. A dark terminal that was only
+// detected keeps chafa's own default, so renders and cache keys stay as they
+// were for everyone who has not asked for a background.
+fn background_color(config: &Config) -> Option<String> {
+    if let Some(bg) = &config.bg {
+        return Some(bg.clone());
+    }
+    let theme = match config.theme {
+        Theme::Auto => match resolve_theme(Theme::Auto, |key| std::env::var(key).ok()) {
+            Theme::Light => Theme::Light,
+            _ => return None,
+        },
+        theme => theme,
+    };
+    Some(theme.bg_color().to_string())
+}
+
+// `LEFTYSAY_THEME` wins over `COLORFGBG` ("fg;bg", where a background of 7 or
+// 15 is a light terminal); anything unrecognised is treated as dark.
+fn resolve_theme(theme: Theme, env: impl Fn(&str) -> Option<String>) -> Theme {
+    if theme != Theme::Auto {
+        return theme;
+    }
+    match env("LEFTYSAY_THEME").as_deref().map(str::trim) {
+        Some("light") => return Theme::Light,
+        Some("dark") => return Theme::Dark,
+        _ => {}
+    }
+    let background =
+        env("COLORFGBG").and_then(|value| value.rsplit(';').next().map(|bg| bg.trim().to_string()));
+    match background.as_deref() {
+        Some("7" | "15") => Theme::Light,
+        _ => Theme::Dark,
+    }
+}
+
+// Picks a graphics protocol from environment hints, falling back to symbols
+// unless a terminal is recognised with confidence.
+fn detect_protocol(env: impl Fn(&str) -> Option<String>) -> ProtocolDetection {
+    let term = env("TERM").unwrap_or_default();
+    let term_program = env("TERM_PROGRAM").unwrap_or_default();
+    let mut signals = Vec::new();
+    if !term.is_empty() {
+        signals.push(format!("TERM={term}"));
+    }
+    if !term_program.is_empty() {
+        signals.push(format!("TERM_PROGRAM={term_program}"));
+    }
+
+    let format = if env("KITTY_WINDOW_ID").is_some() {
+        signals.push("KITTY_WINDOW_ID set".to_string());
+        ChafaFormat::Kitty
+    } else if term.contains("kitty")
+        || term.contains("ghostty")
+        || matches!(term_program.as_str(), "WezTerm" | "ghostty")
+    {
+        ChafaFormat::Kitty
+    } else if term_program == "iTerm.app" || env("LC_TERMINAL").as_deref() == Some("iTerm2") {
+        if term_program != "iTerm.app" {
+            signals.push("LC_TERMINAL=iTerm2".to_string());
+        }
+        ChafaFormat::Iterm2
+    } else if term.contains("sixel") || term.starts_with("mlterm") || term.starts_with("foot") {
+        ChafaFormat::Sixel
+    } else {
+        ChafaFormat::Unicode
+    };
+    ProtocolDetection { format, signals }
+}
+
+/// The arguments leftysay passes to chafa for `image`.
+fn chafa_args(image: &Path, options: &RenderOptions) -> Vec<OsString> {
+    let format = effective_format(options.format);
+    let mut args: Vec<OsString> = vec![image.into()];
+    let mut push = |values: &[&str]| args.extend(values.iter().map(OsString::from));
+    push(&[
+        "--format",
+        format.as_arg(),
+        "--colors",
+        options.colors.as_arg(),
+    ]);
+    push(&["--size", &format!("{}x{}", options.cols, options.rows)]);
+    if let Some(bg) = options.bg {
+        push(&["--bg", bg]);
+    }
+    push(options.sizing.chafa_args());
+    if let Some(dither) = options.dither {
+        push(&["--dither", dither.as_arg()]);
+    }
+    if let Some(grain) = options.dither_grain {
+        push(&["--dither-grain", grain]);
+    }
+    if let Some(symbols) = options.symbols {
+        if format == ChafaFormat::Unicode {
+            push(&["--symbols", symbols.as_arg()]);
+        }
+    }
+    if options.animate {
+        push(&["--animate"]);
+        if options.fps > 0 {
+            push(&["--speed", &format!("{}fps", options.fps)]);
+        }
+        if let Some(ms) = options.duration_ms {
+            push(&["--duration", &format!("{}.{:03}", ms / 1000, ms % 1000)]);
+        }
+    }
+    args.extend(options.extra_args.iter().map(OsString::from));
+    args
+}
+
+/// Formats a command line that can be pasted into a POSIX shell.
+fn shell_command(program: &Path, args: &[OsString]) -> String {
+    std::iter::once(program.as_os_str())
+        .chain(args.iter().map(OsString::as_os_str))
+        .map(|arg| {
+            let arg = arg.to_string_lossy();
+            let plain = !arg.is_empty()
+                && arg
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "-_./:=@%+,".contains(c));
+            if plain {
+                arg.into_owned()
+            } else {
+                format!("'{}'", arg.replace('\'', "'\\''"))
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn run_chafa_once(chafa: &Path, image: &ImageSource, options: &RenderOptions) -> Result<Output> {
+    // chafa always shows the first frame of a still GIF, so other frames are
+    // extracted to a PNG first.
+    let frame_path;
+    let path = match image {
+        ImageSource::Path(path) if options.frame > 0 => {
+            frame_path = extract_gif_frame_to_cache(path, options.frame, options.cache_dir)?;
+            &frame_path
+        }
+        image => image.chafa_path(),
+    };
+    let mut cmd = Command::new(chafa);
+    cmd.args(chafa_args(path, options));
+    let stdin = match image {
+        ImageSource::Path(_) => Stdio::null(),
+        ImageSource::Bytes(_) => Stdio::piped(),
+    };
+    cmd.stdin(stdin)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+
+    info!("running {cmd:?}");
+    let mut child = cmd.spawn().with_context(|| "running chafa")?;
+    // Written from a thread like the output pipes are drained, so neither
+    // side can block the other. chafa may stop reading early on bad input;
+    // that shows up as its own error.
+    let writer = match (image, child.stdin.take()) {
+        (ImageSource::Bytes(bytes), Some(mut pipe)) => {
+            let bytes = Arc::clone(bytes);
+            Some(thread::spawn(move || {
+                let _ = pipe.write_all(&bytes);
+            }))
+        }
+        _ => None,
+    };
+    // Once chafa has exited its end of the pipe is closed, so the writer is
+    // done too.
+    let join_writer = || {
+        if let Some(writer) = writer {
+            let _ = writer.join();
+        }
+    };
+    // Drain the pipes on separate threads so a large render can't fill the
+    // pipe buffer and stall chafa while we wait on it.
+    let stdout = spawn_pipe_reader(child.stdout.take());
+    let stderr = spawn_pipe_reader(child.stderr.take());
+
+    let started = Instant::now();
+    let deadline = started + options.timeout;
+    let mut spinner = Spinner::default();
+    let status = loop {
+        if let Some(status) = child.try_wait().with_context(|| "waiting for chafa")? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            join_writer();
+            return Err(anyhow!(
+                "chafa timed out after {} ms",
+                options.timeout.as_millis()
+            ));
+        }
+        // Quick renders finish before the spinner would flicker into view.
+        if options.spinner && started.elapsed() >= SPINNER_DELAY {
+            spinner.tick(started.elapsed());
+        }
+        thread::sleep(CHAFA_POLL_INTERVAL);
+    };
+    drop(spinner);
+    join_writer();
+
+    Ok(Output {
+        status,
+        stdout: stdout.join().unwrap_or_default(),
+        stderr: stderr.join().unwrap_or_default(),
+    })
+}
+
+// Drawn on stderr while a cold render runs and erased again when dropped,
+// before the image is printed.
+#[derive(Default)]
+struct Spinner {
+    frame: Option<usize>,
+}
+
+impl Spinner {
+    fn tick(&mut self, elapsed: Duration) {
+        let frame = (elapsed.as_millis() / 100) as usize % SPINNER_FRAMES.len();
+        if self.frame != Some(frame) {
+            eprint!("\r{}", SPINNER_FRAMES[frame]);
+            self.frame = Some(frame);
+        }
+    }
+}
+
+impl Drop for Spinner {
+    fn drop(&mut self) {
+        if self.frame.is_some() {
+            eprint!("\r\x1b[K");
+        }
+    }
+}
+
+fn spawn_pipe_reader<R: Read + Send + 'static>(pipe: Option<R>) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut buffer = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut buffer);
+        }
+        buffer
+    })
+}
+
+// Part of the cache key so that upgrading chafa invalidates old renders.
+// chafa is asked for its version once per process.
+fn chafa_identity(chafa: &Path) -> &'static str {
+    static IDENTITY: OnceLock<String> = OnceLock::new();
+    IDENTITY.get_or_init(|| identify_chafa(chafa, chafa_version(chafa)))
+}
+
+// Without a version, the binary's path and mtime stand in for it.
+fn identify_chafa(chafa: &Path, version: Option<String>) -> String {
+    version.unwrap_or_else(|| {
+        format!(
+            "{}@{}",
+            chafa.display(),
+            path_mtime(chafa).unwrap_or_default()
+        )
+    })
+}
+
+fn cache_key(image: &ImageSource, options: &RenderOptions) -> Result<String> {
+    let mut hasher = blake3::Hasher::new();
+    match image {
+        ImageSource::Path(image) => {
+            let meta = fs::metadata(image).with_context(|| "reading image metadata")?;
+            let mtime = meta
+                .modified()
+                .ok()
+                .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+                .map(|d| d.as_secs())
+                .unwrap_or(0);
+            hasher.update(image.to_string_lossy().as_bytes());
+            hasher.update(&mtime.to_le_bytes());
+        }
+        // Streamed images have no path or mtime; the content identifies them.
+        ImageSource::Bytes(bytes) => {
+            hasher.update(b"bytes:");
+            hasher.update(blake3::hash(bytes).as_bytes());
+        }
+    }
+    hasher.update(&options.cols.to_le_bytes());
+    hasher.update(&options.rows.to_le_bytes());
+    hasher.update(effective_format(options.format).as_arg().as_bytes());
+    hasher.update(options.colors.as_arg().as_bytes());
+    hasher.update(&[options.animate as u8]);
+    hasher.update(&options.fps.to_le_bytes());
+    if let Some(ms) = options.duration_ms {
+        hasher.update(b"duration");
+        hasher.update(&ms.to_le_bytes());
+    }
+    for arg in options.extra_args {
+        hasher.update(arg.as_bytes());
+        hasher.update(&[0]);
+    }
+    hasher.update(options.chafa_id.as_bytes());
+    if let Some(bg) = options.bg {
+        hasher.update(b"bg");
+        hasher.update(bg.as_bytes());
+    }
+    if options.frame > 0 {
+        hasher.update(b"frame");
+        hasher.update(&options.frame.to_le_bytes());
+    }
+    for arg in options.sizing.chafa_args() {
+        hasher.update(arg.as_bytes());
+    }
+    if let Some(dither) = options.dither {
+        hasher.update(b"dither");
+        hasher.update(dither.as_arg().as_bytes());
+    }
+    if let Some(grain) = options.dither_grain {
+        hasher.update(b"dither-grain");
+        hasher.update(grain.as_bytes());
+    }
+    if let Some(symbols) = options.symbols {
+        hasher.update(b"symbols");
+        hasher.update(symbols.as_arg().as_bytes());
+    }
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+fn cache_path(cache_dir: &Path, image: &ImageSource, options: &RenderOptions) -> Result<PathBuf> {
+    let cache_key = cache_key(image, options)?;
+    Ok(cache_dir.join(format!("{cache_key}.{CACHE_FILE_EXT}")))
+}
+
+fn cache_dir(config: &Config) -> PathBuf {
+    resolve_cache_dir(config).0
+}
+
+// `LEFTYSAY_CACHE_DIR` wins over `cache_dir` in the config, which wins over
+// the platform cache directory. Also returns where the choice came from.
+fn resolve_cache_dir(config: &Config) -> (PathBuf, &'static str) {
+    resolve_cache_dir_with(std::env::var_os("LEFTYSAY_CACHE_DIR").as_deref(), config)
+}
+
+// `env` is the value of `LEFTYSAY_CACHE_DIR`.
+fn resolve_cache_dir_with(env: Option<&OsStr>, config: &Config) -> (PathBuf, &'static str) {
+    if let Some(dir) = env.filter(|dir| !dir.is_empty()) {
+        return (PathBuf::from(dir), "LEFTYSAY_CACHE_DIR");
+    }
+    if let Some(dir) = &config.cache_dir {
+        return (expand_home(dir), "config");
+    }
+    let dir = ProjectDirs::from("", "", "leftysay")
+        .map(|proj| proj.cache_dir().to_path_buf())
+        .unwrap_or_else(|| PathBuf::from(".cache/leftysay"));
+    (dir, "default")
+}
+
+// Expands a leading `~` to the home directory, like a shell would.
+fn expand_home(path: &Path) -> PathBuf {
+    let Ok(rest) = path.strip_prefix("~") else {
+        return path.to_path_buf();
+    };
+    match BaseDirs::new() {
+        Some(dirs) => dirs.home_dir().join(rest),
+        None => path.to_path_buf(),
+    }
+}
+
+fn ensure_cache_version(cache_dir: &Path) -> Result<()> {
+    let marker = cache_dir.join(CACHE_VERSION_FILE);
+    let current = CACHE_SCHEMA_VERSION.to_string();
+    if fs::read_to_string(&marker).is_ok_and(|stored| stored.trim() == current) {
+        return Ok(());
+    }
+    fs::create_dir_all(cache_dir)?;
+    let lock = fs::File::create(cache_dir.join(CACHE_LOCK_FILE))?;
+    lock.lock()?;
+    // Another process may have finished the wipe while we waited.
+    if fs::read_to_string(&marker).is_ok_and(|stored| stored.trim() == current) {
+        return Ok(());
+    }
+    if let Ok(entries) = fs::read_dir(cache_dir) {
+        for entry in entries.filter_map(Result::ok) {
+            if is_cache_entry(&entry) {
+                let _ = fs::remove_file(entry.path());
+            }
+        }
+    }
+    let _ = fs::remove_file(cache_dir.join(CACHE_INDEX_FILE));
+    let _ = fs::remove_file(cache_dir.join(CACHE_HITS_FILE));
+    let _ = fs::remove_dir_all(cache_dir.join(LEGACY_FRAME_DIR));
+    fs::create_dir_all(cache_dir)?;
+    fs::write(&marker, current)?;
+    Ok(())
+}
+
+// Entries written before compression was added are plain `.txt` files next
+// to the `.txt.gz` path; they are read once and removed.
+fn read_cache_file(path: &Path) -> Option<String> {
+    let mut contents = String::new();
+    if let Ok(file) = fs::File::open(path) {
+        GzDecoder::new(file).read_to_string(&mut contents).ok()?;
+        return Some(contents);
+    }
+    let legacy = path.with_extension("");
+    contents = fs::read_to_string(&legacy).ok()?;
+    let _ = fs::remove_file(&legacy);
+    Some(contents)
+}
+
+fn write_cache_file(path: &Path, contents: &str) -> Result<()> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(contents.as_bytes())?;
+    write_file_atomic(path, &encoder.finish()?)
+}
+
+// Write to a temp file and rename it into place, so concurrent readers
+// never see a half-written file.
+fn write_file_atomic(path: &Path, contents: &[u8]) -> Result<()> {
+    // Unique per process and call, so concurrent writers never share a temp file.
+    static WRITES: AtomicUsize = AtomicUsize::new(0);
+    let tmp_path = path.with_extension(format!(
+        "{}.{}.tmp",
+        std::process::id(),
+        WRITES.fetch_add(1, Ordering::Relaxed)
+    ));
+    fs::write(&tmp_path, contents)?;
+    if let Err(e) = fs::rename(&tmp_path, path) {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+// The cache dir can be shared with other files via `cache_dir`, so only
+// rendered entries (current or legacy) and extracted frames are ever wiped
+// or evicted. Their names are a blake3 hex digest plus the extension.
+fn is_cache_entry(entry: &fs::DirEntry) -> bool {
+    let name = entry.file_name();
+    let name = name.to_string_lossy();
+    let stem = name
+        .strip_suffix(&format!(".{CACHE_FILE_EXT}"))
+        .or_else(|| name.strip_suffix(&format!(".{FRAME_FILE_EXT}")))
+        .or_else(|| name.strip_suffix(".txt"));
+    entry.file_type().is_ok_and(|t| t.is_file())
+        && stem.is_some_and(|stem| {
+            stem.len() == 64 && stem.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+        })
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct CacheIndex {
+    entries: BTreeMap<String, CacheIndexEntry>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct CacheIndexEntry {
+    size: u64,
+    last_used_ms: u64,
+}
+
+fn unix_millis(time: std::time::SystemTime) -> u64 {
+    time.duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+// Sizes and last-use times of the entries, so hits and eviction never stat
+// the whole directory. Rebuilt from the files when missing or unreadable.
+// Hits recorded since the last save are folded in. Callers must hold the
+// cache lock.
+fn load_cache_index(cache_dir: &Path) -> CacheIndex {
+    let stored = fs::read(cache_dir.join(CACHE_INDEX_FILE))
+        .ok()
+        .and_then(|bytes| serde_json::from_slice(&bytes).ok());
+    let mut index = match stored {
+        Some(index) => index,
+        None => {
+            info!("cache: rebuilding index for {}", cache_dir.display());
+            let mut index = CacheIndex::default();
+            reconcile_cache_index(cache_dir, &mut index);
+            index
+        }
+    };
+    if let Ok(hits) = fs::read_to_string(cache_dir.join(CACHE_HITS_FILE)) {
+        for (name, last_used_ms) in hits.lines().filter_map(|line| line.split_once('\t')) {
+            if let (Some(entry), Ok(last_used_ms)) =
+                (index.entries.get_mut(name), last_used_ms.parse::<u64>())
+            {
+                entry.last_used_ms = entry.last_used_ms.max(last_used_ms);
+            }
+        }
+    }
+    index
+}
+
+// Adds entries the index does not know about, e.g. written while another
+// process held the lock, and drops the ones that are gone.
+fn reconcile_cache_index(cache_dir: &Path, index: &mut CacheIndex) {
+    let mut on_disk = BTreeSet::new();
+    for entry in fs::read_dir(cache_dir)
+        .into_iter()
+        .flatten()
+        .filter_map(Result::ok)
+        .filter(is_cache_entry)
+    {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if !index.entries.contains_key(&name) {
+            let Ok(meta) = entry.metadata() else {
+                continue;
+            };
+            let last_used_ms = meta.modified().map_or(0, unix_millis);
+            index.entries.insert(
+                name.clone(),
+                CacheIndexEntry {
+                    size: meta.len(),
+                    last_used_ms,
+                },
+            );
+        }
+        on_disk.insert(name);
+    }
+    index.entries.retain(|name, _| on_disk.contains(name));
+}
+
+// The hits log has been folded into the index, so it starts over. A hit
+// appended in between is lost, which only affects eviction order.
+fn save_cache_index(cache_dir: &Path, index: &CacheIndex) -> Result<()> {
+    write_file_atomic(
+        &cache_dir.join(CACHE_INDEX_FILE),
+        &serde_json::to_vec(index)?,
+    )?;
+    let _ = fs::remove_file(cache_dir.join(CACHE_HITS_FILE));
+    Ok(())
+}
+
+fn cache_entry_name(path: &Path) -> Option<String> {
+    Some(path.file_name()?.to_string_lossy().into_owned())
+}
+
+// Marks an entry as just used by appending to the hits log, which is folded
+// into the index on the next write. Once the log grows large it is folded
+// here, unless another process holds the lock.
+fn touch_cache_entry(path: &Path) -> Result<()> {
+    let (Some(cache_dir), Some(name)) = (path.parent(), cache_entry_name(path)) else {
+        return Ok(());
+    };
+    let mut log = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(cache_dir.join(CACHE_HITS_FILE))?;
+    let now = unix_millis(std::time::SystemTime::now());
+    log.write_all(format!("{name}\t{now}\n").as_bytes())?;
+    if log.metadata()?.len() < CACHE_HITS_COMPACT_BYTES {
+        return Ok(());
+    }
+    let lock = fs::File::create(cache_dir.join(CACHE_LOCK_FILE))?;
+    if lock.try_lock().is_err() {
+        return Ok(());
+    }
+    save_cache_index(cache_dir, &load_cache_index(cache_dir))
+}
+
+// Records a freshly written entry, then evicts the least recently used ones
+// until the cache is within its limits. If another process is already
+// pruning, this run skips it; the entry is picked up by the next one.
+fn enforce_cache_limit(
+    cache_dir: &Path,
+    written: Option<&Path>,
+    max_bytes: u64,
+    max_entries: usize,
+) -> Result<()> {
+    if !cache_dir.exists() {
+        return Ok(());
+    }
+    let lock = fs::File::create(cache_dir.join(CACHE_LOCK_FILE))?;
+    if lock.try_lock().is_err() {
+        return Ok(());
+    }
+
+    let mut index = load_cache_index(cache_dir);
+    reconcile_cache_index(cache_dir, &mut index);
+    if let Some(path) = written {
+        if let (Some(name), Ok(meta)) = (cache_entry_name(path), fs::metadata(path)) {
+            index.entries.insert(
+                name,
+                CacheIndexEntry {
+                    size: meta.len(),
+                    last_used_ms: unix_millis(std::time::SystemTime::now()),
+                },
+            );
+        }
+    }
+
+    let mut total_size: u64 = index.entries.values().map(|entry| entry.size).sum();
+    // 0 means no limit on the number of entries.
+    let within_limits =
+        |size: u64, count: usize| size <= max_bytes && (max_entries == 0 || count <= max_entries);
+    if !within_limits(total_size, index.entries.len()) {
+        let mut by_age: Vec<(String, CacheIndexEntry)> = index
+            .entries
+            .iter()
+            .map(|(name, entry)| (name.clone(), *entry))
+            .collect();
+        by_age.sort_by_key(|(_, entry)| entry.last_used_ms);
+        for (name, entry) in by_age {
+            if within_limits(total_size, index.entries.len()) {
+                break;
+            }
+            match fs::remove_file(cache_dir.join(&name)) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => continue,
+                _ => {}
+            }
+            index.entries.remove(&name);
+            total_size = total_size.saturating_sub(entry.size);
+        }
+    }
+
+    save_cache_index(cache_dir, &index)
+}
+
+fn run_bench<W: Write + ?Sized>(
+    out: &mut W,
+    chafa: &Path,
+    config: &Config,
+    image: Option<&Path>,
+) -> Result<()> {
+    let (image, preferred) = match image {
+        Some(path) => (path.to_path_buf(), None),
+        None => {
+            let packs = scan_packs(config)?.packs;
+            let blocklist =
+                build_globset(&config.image_blocklist).context("parsing image_blocklist")?;
+            let pack = resolve_pack(
+                &config.default_pack,
+                &packs,
+                config.pack_selection,
+                None,
+                blocklist.as_ref(),
+            )?
+            .ok_or_else(|| anyhow!("pack not found: {}", config.default_pack))?;
+            let image = pack
+                .images
+                .first()
+                .cloned()
+                .ok_or_else(|| anyhow!("pack {} has no images", pack.meta.name))?;
+            let preferred = (
+                pack_format(config.format, Some(&pack.meta)),
+                pack_colors(config.colors, Some(&pack.meta)),
+            );
+            (image, Some(preferred))
+        }
+    };
+
+    let source = ImageSource::Path(image.clone());
+    let (term_cols, term_rows) = terminal_dimensions();
+    let bg = background_color(config);
+    let cache_dir = cache_dir(config);
+    let base = RenderOptions {
+        animate: false,
+        cache_enabled: false,
+        ..typical_render_options(
+            chafa,
+            config,
+            term_cols,
+            term_rows,
+            bg.as_deref(),
+            &cache_dir,
+        )?
+    };
+    writeln!(
+        out,
+        "bench: {} at {}x{}",
+        image.display(),
+        base.cols,
+        base.rows
+    )?;
+    if let Some((format, colors)) = preferred {
+        writeln!(
+            out,
+            "pack prefers {} with {} colors (marked *)",
+            format.as_arg(),
+            colors.as_arg()
+        )?;
+    }
+    writeln!(
+        out,
+        "{:<8} {:<6} {:>8} {:>10}",
+        "format", "colors", "ms", "bytes"
+    )?;
+    let formats = [
+        ChafaFormat::Unicode,
+        ChafaFormat::Kitty,
+        ChafaFormat::Iterm2,
+        ChafaFormat::Sixel,
+    ];
+    let colors = [
+        ChafaColors::Truecolor,
+        ChafaColors::C256,
+        ChafaColors::C16,
+        ChafaColors::None,
+    ];
+    for format in formats {
+        for colors in colors {
+            let options = RenderOptions {
+                format,
+                colors,
+                ..base
+            };
+            // A single attempt, so a failing format is never timed as one of
+            // the fallbacks.
+            let started = Instant::now();
+            let result = run_chafa_once(chafa, &source, &options);
+            let elapsed = started.elapsed().as_millis();
+            let mark = if preferred == Some((format, colors)) {
+                "  *"
+            } else {
+                ""
+            };
+            let (format, colors) = (format.as_arg(), colors.as_arg());
+            let reason = match result {
+                Ok(output) if output.status.success() => {
+                    writeln!(
+                        out,
+                        "{format:<8} {colors:<6} {elapsed:>8} {:>10}{mark}",
+                        output.stdout.len()
+                    )?;
+                    continue;
+                }
+                Ok(output) => String::from_utf8_lossy(&output.stderr)
+                    .lines()
+                    .next()
+                    .map_or_else(|| output.status.to_string(), str::to_string),
+                Err(err) => err.to_string(),
+            };
+            writeln!(
+                out,
+                "{format:<8} {colors:<6} {:>8} {:>10}  {reason}",
+                "-", "failed"
+            )?;
+        }
+    }
+    Ok(())
+}
+
+// Options for rendering a pack image for a typical one-line greeting, so
+// warmed renders and the doctor's sample command match normal runs.
+fn typical_render_options<'a>(
+    chafa: &Path,
+    config: &'a Config,
+    term_cols: usize,
+    term_rows: usize,
+    bg: Option<&'a str>,
+    cache_dir: &'a Path,
+) -> Result<RenderOptions<'a>> {
+    let bubble_options = BubbleOptions {
+        max_width: config.bubble_max_width,
+        padding: config.bubble_padding,
+        glyphs: config.bubble.chars.glyphs()?,
+        ..BubbleOptions::default()
+    };
+    let bubble_height = render_bubble(&config.default_message, term_cols, &bubble_options).len();
+    let rows = image_row_budget(term_rows, config.max_height_ratio, bubble_height).max(1);
+    Ok(RenderOptions {
+        cols: term_cols,
+        rows,
+        format: config.format,
+        colors: if no_color() {
+            ChafaColors::None
+        } else {
+            config.colors
+        },
+        animate: config.animate,
+        fps: 0,
+        duration_ms: None,
+        cache_enabled: config.cache,
+        cache_max_mb: config.cache_max_mb,
+        cache_max_entries: config.cache_max_entries,
+        cache_dir,
+        frame: 0,
+        timeout: Duration::from_millis(config.chafa_timeout_ms),
+        extra_args: &config.chafa_extra_args,
+        chafa_id: chafa_identity(chafa),
+        sizing: config.sizing,
+        dither: config.dither,
+        dither_grain: config.dither_grain.as_deref(),
+        symbols: config.symbols,
+        bg,
+        spinner: false,
+        format_fallbacks: &config.format_fallbacks,
+    })
+}
+
+fn warm_cache(chafa: &Path, config: &Config, pack_name: Option<&str>) -> Result<()> {
+    let packs = scan_packs(config)?.packs;
+    let selected: Vec<&Pack> = match pack_name {
+        Some(name) => vec![packs
+            .iter()
+            .find(|p| p.meta.name == name)
+            .ok_or_else(|| anyhow!("pack not found: {name}"))?],
+        None => packs.iter().collect(),
+    };
+
+    let (term_cols, term_rows) = terminal_dimensions();
+    let bg = background_color(config);
+    let cache_dir = cache_dir(config);
+    let base = typical_render_options(
+        chafa,
+        config,
+        term_cols,
+        term_rows,
+        bg.as_deref(),
+        &cache_dir,
+    )?;
+
+    let (mut rendered, mut cached) = (0usize, 0usize);
+    for pack in selected {
+        for image in &pack.images {
+            let animated = config.animate && is_animated_image(image);
+            let options = RenderOptions {
+                fps: if animated { config.fps } else { 0 },
+                duration_ms: if animated {
+                    loops_duration_ms(image, config.fps, config.loops)
+                } else {
+                    None
+                },
+                cache_enabled: true,
+                format: pack_format(base.format, Some(&pack.meta)),
+                colors: pack_colors(base.colors, Some(&pack.meta)),
+                ..base
+            };
+            let source = ImageSource::Path(image.clone());
+            if cache_path(&cache_dir, &source, &options)?.exists() {
+                cached += 1;
+                continue;
+            }
+            render_image(chafa, &source, options)
+                .with_context(|| format!("rendering {}", image.display()))?;
+            rendered += 1;
+        }
+    }
+
+    enforce_cache_limit(
+        &cache_dir,
+        None,
+        config.cache_max_mb * 1024 * 1024,
+        config.cache_max_entries,
+    )?;
+    println!("warm-cache: {rendered} rendered, {cached} already cached");
+    Ok(())
+}
+
+fn print_pack_list(out: &mut dyn Write, packs: &[Pack], details: bool) -> Result<()> {
+    if packs.is_empty() {
+        writeln!(out, "No packs found.")?;
+        return Ok(());
+    }
+    for pack in packs {
+        writeln!(
+            out,
+            "{} (v{}, {}): {}{}",
+            pack.meta.name,
+            pack.meta.version,
+            pack.meta.license,
+            pack.meta.description,
+            if pack.meta.enabled { "" } else { " [disabled]" }
+        )?;
+        for image in &pack.images {
+            let Some(name) = image.file_name().and_then(OsStr::to_str) else {
+                continue;
+            };
+            if details {
+                let info = image_details(image);
+                let size = match (info.width, info.height) {
+                    (Some(width), Some(height)) => format!("{width}x{height}"),
+                    _ => "?x?".to_string(),
+                };
+                let bytes = info.bytes.map_or("?".to_string(), format_size);
+                writeln!(out, "  - {name}  {size}  {bytes}")?;
+            } else {
+                writeln!(out, "  - {name}")?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn print_pack_list_json(out: &mut dyn Write, packs: &[Pack], details: bool) -> Result<()> {
+    let entries: Vec<PackListEntry> = packs
+        .iter()
+        .map(|pack| PackListEntry {
+            meta: &pack.meta,
+            image_count: pack.images.len(),
+            images: pack
+                .images
+                .iter()
+                .filter_map(|image| image.file_name().and_then(OsStr::to_str))
+                .map(str::to_string)
+                .collect(),
+            image_details: if details {
+                pack.images
+                    .iter()
+                    .map(|image| image_details(image))
+                    .collect()
+            } else {
+                Vec::new()
+            },
+        })
+        .collect();
+    writeln!(out, "{}", serde_json::to_string_pretty(&entries)?)?;
+    Ok(())
+}
+
+#[derive(Clone, Copy, Debug)]
+struct RenderOptions<'a> {
+    cols: usize,
+    rows: usize,
+    format: ChafaFormat,
+    colors: ChafaColors,
+    animate: bool,
+    fps: u32,
+    // How long chafa plays an animation, covering all `--loops`.
+    duration_ms: Option<u64>,
+    cache_enabled: bool,
+    cache_max_mb: u64,
+    cache_max_entries: usize,
+    cache_dir: &'a Path,
+    frame: usize,
+    timeout: Duration,
+    extra_args: &'a [String],
+    chafa_id: &'a str,
+    sizing: Sizing,
+    dither: Option<Dither>,
+    dither_grain: Option<&'a str>,
+    symbols: Option<SymbolSet>,
+    bg: Option<&'a str>,
+    spinner: bool,
+    format_fallbacks: &'a [ChafaFormat],
+}
+
+fn sample_chafa_command(chafa: &Path, cols: usize, rows: usize, config: &Config) -> Result<String> {
+    let bg = background_color(config);
+    let cache_dir = cache_dir(config);
+    let options = typical_render_options(chafa, config, cols, rows, bg.as_deref(), &cache_dir)?;
+    Ok(shell_command(
+        chafa,
+        &chafa_args(Path::new("<image>"), &options),
+    ))
+}
+
+fn print_doctor(
+    out: &mut dyn Write,
+    chafa: &Path,
+    cols: usize,
+    rows: usize,
+    config: &Config,
+    scan: &PackScan,
+    seed: (Option<u64>, SeedSource),
+) -> Result<()> {
+    writeln!(out, "leftysay doctor")?;
+    writeln!(out, "chafa: {}", chafa.display())?;
+    match chafa_version(chafa) {
+        Some(version) => {
+            writeln!(out, "chafa version: {version}")?;
+            let format = config.format;
+            if let (Some(found), Some(minimum)) =
+                (parse_version(&version), min_chafa_version(format))
+            {
+                if found < minimum {
+                    writeln!(
+                        out,
+                        "warning: format {} needs chafa {}.{}.{} or newer",
+                        format.as_arg(),
+                        minimum.0,
+                        minimum.1,
+                        minimum.2
+                    )?;
+                }
+            }
+        }
+        None => writeln!(out, "chafa: version unknown")?,
+    }
+    writeln!(out, "terminal: {} cols x {} rows", cols, rows)?;
+    if std::io::stdout().is_terminal() {
+        writeln!(out, "stdout: terminal")?;
+    } else {
+        writeln!(
+            out,
+            "stdout: not a terminal (images are skipped unless --force-render)"
+        )?;
+    }
+    writeln!(out, "config.format: {}", config.format.as_arg())?;
+    let detection = detect_protocol(|name| std::env::var(name).ok());
+    let signals = if detection.signals.is_empty() {
+        "no signals".to_string()
+    } else {
+        detection.signals.join(", ")
+    };
+    writeln!(
+        out,
+        "detected protocol: {} ({signals})",
+        detection.format.as_arg()
+    )?;
+    writeln!(out, "config.colors: {}", config.colors.as_arg())?;
+    writeln!(
+        out,
+        "format/colors precedence: command line > config (unless auto) > pack format/colors > auto"
+    )?;
+    let pack_choices: Vec<String> = scan
+        .packs
+        .iter()
+        .filter(|pack| pack.meta.format.is_some() || pack.meta.colors.is_some())
+        .map(|pack| {
+            let format = pack.meta.format.map_or("-", ChafaFormat::as_arg);
+            let colors = pack.meta.colors.map_or("-", ChafaColors::as_arg);
+            format!("{} ({format}/{colors})", pack.meta.name)
+        })
+        .collect();
+    if !pack_choices.is_empty() {
+        writeln!(
+            out,
+            "packs with their own format/colors: {}",
+            pack_choices.join(", ")
+        )?;
+    }
+    if no_color() {
+        writeln!(out, "NO_COLOR: set (colors forced to none)")?;
+    }
+    if disabled_by_env() {
+        writeln!(out, "LEFTYSAY_DISABLE: set (normal runs print nothing)")?;
+    }
+    writeln!(out, "config.max_height_ratio: {}", config.max_height_ratio)?;
+    writeln!(out, "config.cache: {}", config.cache)?;
+    writeln!(out, "config.cache_max_mb: {}", config.cache_max_mb)?;
+    writeln!(
+        out,
+        "config.cache_max_entries: {}",
+        config.cache_max_entries
+    )?;
+    writeln!(out, "config.chafa_timeout_ms: {}", config.chafa_timeout_ms)?;
+    writeln!(
+        out,
+        "chafa command: {}",
+        sample_chafa_command(chafa, cols, rows, config)?
+    )?;
+    match seed {
+        (Some(value), source) => writeln!(out, "seed: {value} (from {})", source.describe())?,
+        (None, source) => writeln!(out, "seed: none (from {})", source.describe())?,
+    }
+
+    if config.files.is_empty() {
+        writeln!(out, "config files: none, using defaults")?;
+    } else {
+        writeln!(out, "config files (merged in order):")?;
+        for path in &config.files {
+            writeln!(out, "  {}", path.display())?;
+        }
+    }
+    if let Some(proj_dirs) = ProjectDirs::from("", "", "leftysay") {
+        writeln!(out, "config dir: {}", proj_dirs.config_dir().display())?;
+        writeln!(out, "data dir: {}", proj_dirs.data_dir().display())?;
+    }
+    let (cache_dir, cache_dir_source) = resolve_cache_dir(config);
+    writeln!(
+        out,
+        "cache dir: {} (from {cache_dir_source})",
+        cache_dir.display()
+    )?;
+    writeln!(out, "pack search paths:")?;
+    for path in pack_search_paths() {
+        writeln!(out, "  - {}", path.display())?;
+    }
+    writeln!(out, "packs found: {}", scan.packs.len())?;
+    for empty in &scan.empty {
+        writeln!(out, "warning: {}", describe_empty_pack(empty))?;
+    }
+    for shadowed in &scan.shadowed {
+        writeln!(out, "warning: {}", describe_shadowed_pack(shadowed))?;
+    }
+    for reserved in &scan.reserved {
+        writeln!(out, "warning: {}", describe_reserved_pack(reserved))?;
+    }
+
+    Ok(())
+}
+
+fn print_doctor_json(
+    out: &mut dyn Write,
+    chafa: &Path,
+    cols: usize,
+    rows: usize,
+    config: &Config,
+    scan: &PackScan,
+    seed: (Option<u64>, SeedSource),
+) -> Result<()> {
+    let proj_dirs = ProjectDirs::from("", "", "leftysay");
+    let detection = detect_protocol(|name| std::env::var(name).ok());
+    let (cache_dir, cache_dir_source) = resolve_cache_dir(config);
+    let report = DoctorReport {
+        chafa,
+        chafa_version: chafa_version(chafa),
+        chafa_command: sample_chafa_command(chafa, cols, rows, config)?,
+        detected_format: detection.format,
+        detection_signals: detection.signals,
+        terminal_cols: cols,
+        terminal_rows: rows,
+        no_color: no_color(),
+        disabled_by_env: disabled_by_env(),
+        stdout_tty: std::io::stdout().is_terminal(),
+        seed: seed.0,
+        seed_source: seed.1,
+        config,
+        config_files: &config.files,
+        config_dir: proj_dirs.as_ref().map(|p| p.config_dir().to_path_buf()),
+        data_dir: proj_dirs.as_ref().map(|p| p.data_dir().to_path_buf()),
+        cache_dir,
+        cache_dir_source,
+        pack_search_paths: pack_search_paths()
+            .into_iter()
+            .map(|path| SearchPathReport {
+                exists: path.exists(),
+                path,
+            })
+            .collect(),
+        packs_found: scan.packs.len(),
+        skipped_packs: &scan.empty,
+        shadowed_packs: &scan.shadowed,
+        reserved_packs: &scan.reserved,
+    };
+    writeln!(out, "{}", serde_json::to_string_pretty(&report)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn bubble_renders_multiple_lines() {
+        let lines = render_bubble("hello\tworld from leftysay", 40, &BubbleOptions::default());
+        assert!(lines.len() >= 3);
+        assert!(lines.first().unwrap().contains('_'));
+        assert!(lines.iter().any(|line| line.contains('-')));
+        assert!(lines.iter().any(|line| line.trim().starts_with('o')));
+    }
+
+    fn test_options(cols: usize, rows: usize) -> RenderOptions<'static> {
+        RenderOptions {
+            cols,
+            rows,
+            format: ChafaFormat::Auto,
+            colors: ChafaColors::Auto,
+            animate: false,
+            fps: 0,
+            duration_ms: None,
+            cache_enabled: true,
+            cache_max_mb: DEFAULT_CACHE_MAX_MB,
+            cache_max_entries: DEFAULT_CACHE_MAX_ENTRIES,
+            cache_dir: Path::new(""),
+            frame: 0,
+            timeout: Duration::from_millis(DEFAULT_CHAFA_TIMEOUT_MS),
+            extra_args: &[],
+            chafa_id: "",
+            sizing: Sizing::Fit,
+            dither: None,
+            dither_grain: None,
+            symbols: None,
+            bg: None,
+            spinner: false,
+            format_fallbacks: &[],
+        }
+    }
+
+    #[test]
+    fn bubble_width_is_configurable() {
+        let text = "word ".repeat(40);
+        let narrow = render_bubble(
+            &text,
+            200,
+            &BubbleOptions {
+                max_width: 20,
+                ..BubbleOptions::default()
+            },
+        );
+        assert!(narrow[0].len() <= 20 + 3);
+        let unlimited = render_bubble(
+            &text,
+            200,
+            &BubbleOptions {
+                max_width: 0,
+                ..BubbleOptions::default()
+            },
+        );
+        assert!(unlimited[0].len() > DEFAULT_BUBBLE_MAX_WIDTH + 3);
+        let clamped = render_bubble(
+            &text,
+            50,
+            &BubbleOptions {
+                max_width: 500,
+                ..BubbleOptions::default()
+            },
+        );
+        let body = &clamped[..clamped.len() - 3];
+        assert!(body.iter().all(|line| line.len() <= 50));
+    }
+
+    #[test]
+    fn message_is_truncated_at_grapheme_boundaries() {
+        assert_eq!(truncate_message("hello".into(), 0), "hello");
+        assert_eq!(truncate_message("hello".into(), 5), "hello");
+        assert_eq!(truncate_message("hello world".into(), 5), "hell…");
+
+        let family = "👨‍👩‍👧";
+        let message = format!("hi {family}{family} there");
+        assert_eq!(
+            truncate_message(message.clone(), 5),
+            format!("hi {family}…")
+        );
+        assert_eq!(truncate_message(message, 4), "hi …");
+    }
+
+    #[test]
+    fn long_words_break_or_truncate_within_the_border() {
+        let url = format!("https://example.com/{}", "a".repeat(80));
+        assert_eq!(url.len(), 100);
+        let text = format!("see {url} now");
+        for long_word in [LongWord::Break, LongWord::Truncate] {
+            let options = BubbleOptions {
+                long_word,
+                tail: TailPosition::None,
+                ..BubbleOptions::default()
+            };
+            let bubble = render_bubble(&text, 40, &options);
+            let width = visible_width(&bubble[1]);
+            assert!(width <= 40);
+            assert!(bubble[1..bubble.len() - 1]
+                .iter()
+                .all(|line| visible_width(line) == width));
+            if long_word == LongWord::Truncate {
+                // The URL is cut to one bubble width on a line of its own.
+                assert_eq!(bubble.len(), 5);
+                assert!(bubble[1].contains("see "));
+                assert!(bubble[2].contains("https://example.com/aaa"));
+                assert!(bubble[2].contains("a... "));
+                assert!(bubble[3].contains("now "));
+            } else {
+                assert!(bubble.len() > 4);
+            }
+        }
+
+        assert_eq!(truncate_long_words("abcdef cd", 4), vec!["a...", "cd"]);
+        // Too narrow to keep a character, so the word is left to wrap.
+        assert_eq!(truncate_long_words("ab abcdef", 3), vec!["ab", "abcdef"]);
+        // One wide character does not exempt a long URL; a CJK run does.
+        let mixed = format!("https://例.com/{}", "a".repeat(40));
+        assert_eq!(
+            truncate_long_words(&mixed, 20),
+            vec!["https://例.com/aa..."]
+        );
+        let cjk = "漢字".repeat(20);
+        assert_eq!(truncate_long_words(&cjk, 20), vec![cjk.clone()]);
+    }
+
+    #[test]
+    fn truncated_colored_words_keep_their_escapes_whole() {
+        let url = format!("https://example.com/{}", "a".repeat(80));
+        let colored = format!("\x1b[34m{url}\x1b[0m");
+        let options = BubbleOptions {
+            long_word: LongWord::Truncate,
+            tail: TailPosition::None,
+            ..BubbleOptions::default()
+        };
+        let bubble = render_bubble(&format!("see {colored} now"), 40, &options);
+        assert_eq!(bubble.len(), 5);
+        let width = visible_width(&bubble[1]);
+        assert!(bubble[1..4].iter().all(|line| visible_width(line) == width));
+
+        let cut = &bubble[2];
+        assert!(cut.contains("\x1b[34mhttps://example.com/aaa"));
+        let shown = strip_ansi(cut);
+        assert!(shown.contains(&format!("{}...", &url[..33])));
+        assert!(!shown.contains(&url[..34]));
+        // The color is reset before the padding and the border.
+        let reset = cut.rfind("\x1b[0m").unwrap();
+        assert!(!cut[reset + 4..].contains('\x1b'));
+        assert!(!bubble[3].contains('\x1b'));
+    }
+
+    #[test]
+    fn speaker_uses_two_column_eyes_and_tongue() {
+        let cow = render_speaker(2, "oo", "  ");
+        assert_eq!(cow[0], "  ^__^");
+        assert_eq!(cow[1], "  (oo)\\_______");
+        assert_eq!(cow[3], "      ||----w |");
+
+        let cow = render_speaker(0, "@@@", "U");
+        assert_eq!(cow[1], "(@@)\\_______");
+        assert_eq!(cow[3], " U  ||----w |");
+        assert_eq!(render_speaker(0, "目", "")[1], "(目)\\_______");
+    }
+
+    #[test]
+    fn rtl_bubble_right_aligns_lines() {
+        let text = "שלום\nעולם טוב";
+        assert!(Direction::Auto.is_rtl(text));
+        assert!(!Direction::Auto.is_rtl("123 hello שלום"));
+        assert!(Direction::Rtl.is_rtl("hello"));
+
+        let options = BubbleOptions {
+            rtl: true,
+            tail: TailPosition::Left,
+            ..BubbleOptions::default()
+        };
+        let bubble = render_bubble(text, 80, &options);
+        assert_eq!(bubble[1], "/     שלום \\");
+        assert_eq!(bubble[2], "\\ עולם טוב /");
+        assert_eq!(visible_width(&bubble[1]), visible_width(&bubble[2]));
+        let tail = bubble.last().unwrap();
+        assert!(visible_width(tail) > visible_width(&bubble[2]));
+    }
+
+    #[test]
+    fn bubble_padding_widens_borders() {
+        let options = BubbleOptions {
+            padding: 3,
+            ..BubbleOptions::default()
+        };
+        let lines = render_bubble("hi", 80, &options);
+        assert_eq!(lines[0], format!(" {}", "_".repeat(8)));
+        assert_eq!(lines[1], "<   hi   >");
+        assert_eq!(lines[2], format!(" {}", "-".repeat(8)));
+    }
+
+    #[test]
+    fn bubble_shows_right_aligned_speaker() {
+        let options = BubbleOptions {
+            speaker: Some("Tux"),
+            ..BubbleOptions::default()
+        };
+        let plain = render_bubble("hello there", 80, &BubbleOptions::default());
+        let lines = render_bubble("hello there", 80, &options);
+        assert_eq!(lines.len(), plain.len() + 1);
+        assert_eq!(lines[..3], plain[..3]);
+        assert_eq!(lines[3], "         -- Tux");
+        assert_eq!(lines[3].len(), lines[1].len());
+    }
+
+    #[test]
+    fn bubble_uses_custom_glyphs() {
+        let config: Config = toml::from_str(
+            "[bubble.chars]\ntop = \"=\"\nleft = \"!\"\nright = \"!\"\ntop_left = \"+\"\n",
+        )
+        .unwrap();
+        let options = BubbleOptions {
+            glyphs: config.bubble.chars.glyphs().unwrap(),
+            ..BubbleOptions::default()
+        };
+        let lines = render_bubble("one\ntwo\nthree", 80, &options);
+        assert_eq!(lines[0], " =======");
+        assert_eq!(lines[1], "+ one   \\");
+        assert_eq!(lines[2], "! two   !");
+        assert_eq!(lines[3], "\\ three /");
+        assert_eq!(lines[4], " -------");
+
+        let wide: Config = toml::from_str("[bubble.chars]\ntop = \"==\"\n").unwrap();
+        assert!(wide.bubble.chars.glyphs().is_err());
+        let cjk: Config = toml::from_str("[bubble.chars]\ntop = \"\u{6f22}\"\n").unwrap();
+        assert!(cjk.bubble.chars.glyphs().is_err());
+    }
+
+    #[test]
+    fn bubble_wraps_wide_characters_by_display_width() {
+        let text = "\u{6f22}\u{5b57}".repeat(25);
+        let options = BubbleOptions {
+            max_width: 40,
+            ..BubbleOptions::default()
+        };
+        let lines = render_bubble(&text, 80, &options);
+        let border_width = visible_width(&lines[0]);
+        assert_eq!(border_width, 1 + 40 + 2);
+        let body: Vec<&String> = lines[1..]
+            .iter()
+            .take_while(|l| !l.starts_with(" -"))
+            .collect();
+        assert_eq!(body.len(), 3);
+        for line in &body {
+            assert_eq!(visible_width(line), border_width + 1);
+        }
+        assert_eq!(body[0].chars().filter(|c| *c == '\u{6f22}').count(), 10);
+    }
+
+    #[test]
+    fn bubble_ignores_ansi_codes_in_width() {
+        let lines = render_bubble(
+            "\x1b[31mred\x1b[0m\nplain text",
+            80,
+            &BubbleOptions::default(),
+        );
+        assert_eq!(lines[0], format!(" {}", "_".repeat(12)));
+        assert_eq!(lines[1], "/ \x1b[31mred\x1b[0m        \\");
+        assert_eq!(lines[2], "\\ plain text /");
+    }
+
+    #[test]
+    fn rainbow_keeps_bubble_geometry() {
+        let plain = render_bubble("hello rainbow\nsecond", 80, &BubbleOptions::default());
+        let colored = render_bubble(
+            "hello rainbow\nsecond",
+            80,
+            &BubbleOptions {
+                rainbow: Some(1.0),
+                ..BubbleOptions::default()
+            },
+        );
+        assert_eq!(plain[0], colored[0]);
+        assert!(colored[1].contains("\x1b[38;2;"));
+        assert!(colored[1].starts_with("/ "));
+        assert_eq!(strip_ansi(&colored[1]), plain[1]);
+        assert_eq!(strip_ansi(&colored[2]), plain[2]);
+    }
+
+    #[test]
+    fn rainbow_is_kept_without_an_image() {
+        let cli = Cli::parse_from(["leftysay", "--no-image", "--rainbow", "--rainbow-seed", "3"]);
+        let phase = rainbow_phase(&cli, true, ChafaColors::Auto, None);
+        assert!(phase.is_some());
+        let bubble = render_bubble(
+            "hello",
+            80,
+            &BubbleOptions {
+                rainbow: phase,
+                ..BubbleOptions::default()
+            },
+        );
+        assert!(bubble[1].contains("\x1b[38;2;"));
+
+        assert!(rainbow_phase(&cli, true, ChafaColors::None, None).is_none());
+        assert!(rainbow_phase(&cli, false, ChafaColors::Auto, None).is_none());
+    }
+
+    #[test]
+    fn ascii_bubble_only_emits_ascii() {
+        let config: Config = toml::from_str("[bubble.chars]\ntop = \"\u{2500}\"\n").unwrap();
+        let options = BubbleOptions {
+            glyphs: config.bubble.chars.glyphs().unwrap(),
+            speaker: Some("T\u{fc}x"),
+            ascii: true,
+            ..BubbleOptions::default()
+        };
+        let lines = render_bubble("caf\u{e9} \u{6f22}\u{5b57}\nok", 80, &options);
+        assert!(lines.iter().all(|line| line.is_ascii()));
+        assert_eq!(lines[1], "/ caf? ?? \\");
+        assert!(lines.iter().any(|line| line.ends_with("-- T?x")));
+    }
+
+    #[test]
+    fn ansi_output_converts_to_html() {
+        let html = ansi_to_html("\x1b[?25l\x1b[38;2;255;0;0;48;5;16mA\x1b[0m<b>");
+        assert_eq!(
+            html,
+            "<span style=\"color:#ff0000;background-color:#000000;\">A</span>&lt;b&gt;"
+        );
+        let link = ansi_to_html(&attribution_line("art", "https://x.test/?a&b", false));
+        assert_eq!(link, "<a href=\"https://x.test/?a&amp;b\">art: art</a>");
+    }
+
+    #[test]
+    fn attribution_links_the_pack_url() {
+        let meta = parse_pack_meta(
+            "name = \"art\"\nversion = \"1.0.0\"\nlicense = \"CC-BY-4.0\"\ndescription = \"d\"\nimages_dir = \"images\"\nattribution_url = \"https://example.com/art\"\n",
+        )
+        .unwrap();
+        let url = meta.attribution_url.as_deref().unwrap();
+        assert_eq!(url, "https://example.com/art");
+
+        let plain = attribution_line(&meta.name, url, false);
+        assert_eq!(
+            plain,
+            "\x1b]8;;https://example.com/art\x1b\\art: art\x1b]8;;\x1b\\"
+        );
+        assert_eq!(strip_ansi(&plain), "art: art");
+        assert_eq!(
+            attribution_line(&meta.name, url, true),
+            format!("\x1b[2m{plain}\x1b[0m")
+        );
+        let unlinked = parse_pack_meta(
+            "name = \"p\"\nversion = \"1\"\nlicense = \"MIT\"\ndescription = \"d\"\nimages_dir = \".\"\n",
+        )
+        .unwrap();
+        assert!(unlinked.attribution_url.is_none());
+    }
+
+    #[test]
+    fn credit_names_pack_version_license_and_file() {
+        let pack = test_pack("tux");
+        let image = Path::new("/packs/tux/images/wave.png");
+        assert_eq!(
+            credit_line(&pack.meta, image),
+            "tux 0.1.0 (CC0-1.0) - wave.png"
+        );
+    }
+
+    #[test]
+    fn stats_report_cache_use_and_output_size() {
+        let stats = |rendered: Option<&RenderedImage>| {
+            let bubble = vec!["< hi >".to_string(), " ----".to_string()];
+            let image = rendered.map_or("", |r| r.text.as_str());
+            let mut out = Vec::new();
+            write_stats(&mut out, rendered, &bubble, image).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        let miss = RenderedImage {
+            text: "\x1b[31m########\x1b[0m\n########\n".to_string(),
+            cache_hit: false,
+            elapsed: Duration::from_millis(42),
+        };
+        assert_eq!(
+            stats(Some(&miss)),
+            "stats: cache miss, chafa took 42 ms\nstats: render size 27 bytes\nstats: output 8 cols x 4 rows\n"
+        );
+        let hit = RenderedImage {
+            cache_hit: true,
+            ..miss
+        };
+        assert!(stats(Some(&hit)).starts_with("stats: cache hit\n"));
+        assert_eq!(
+            stats(None),
+            "stats: image skipped\nstats: render size 0 bytes\nstats: output 6 cols x 2 rows\n"
+        );
+    }
+
+    #[test]
+    fn long_message_is_truncated_to_max_bubble_lines() {
+        let text = "lorem ipsum dolor sit amet ".repeat(60);
+        let full = render_bubble(&text, 80, &BubbleOptions::default());
+        assert!(full.len() > 24);
+        assert_eq!(
+            image_row_budget(24, DEFAULT_MAX_HEIGHT_RATIO, full.len()),
+            0
+        );
+
+        let options = BubbleOptions {
+            max_lines: 5,
+            ..BubbleOptions::default()
+        };
+        let lines = render_bubble(&text, 80, &options);
+        // Top border, 5 text lines, bottom border and a 3-line tail.
+        assert_eq!(lines.len(), 10);
+        assert!(lines[5].trim_end_matches(['/', ' ']).ends_with("..."));
+        assert_eq!(visible_width(&lines[5]), visible_width(&lines[1]));
+        assert!(image_row_budget(24, DEFAULT_MAX_HEIGHT_RATIO, lines.len()) > 0);
+    }
+
+    #[test]
+    fn bubble_below_image_has_tail_on_top() {
+        let down = render_bubble("hi", 80, &BubbleOptions::default());
+        let up = render_bubble(
+            "hi",
+            80,
+            &BubbleOptions {
+                tail_up: true,
+                ..BubbleOptions::default()
+            },
+        );
+        assert_eq!(up.len(), down.len());
+        assert_eq!(up[0], down[down.len() - 1]);
+        assert_eq!(up[2], down[3]);
+        assert_eq!(up[3], down[0]);
+    }
+
+    #[test]
+    fn bubble_tail_follows_tail_position() {
+        let tail_start = |tail| {
+            let lines = render_bubble(
+                "hello",
+                80,
+                &BubbleOptions {
+                    tail,
+                    ..BubbleOptions::default()
+                },
+            );
+            lines.get(3).map(|line| line.find('o').unwrap())
+        };
+        // "< hello >" spans columns 0-8.
+        assert_eq!(tail_start(TailPosition::Right), Some(11));
+        assert_eq!(tail_start(TailPosition::Center), Some(5));
+        assert_eq!(tail_start(TailPosition::Left), Some(2));
+        assert_eq!(tail_start(TailPosition::None), None);
+    }
+
+    #[test]
+    fn bubble_keeps_explicit_newlines() {
+        let lines = render_bubble(
+            "short\na much longer second line",
+            40,
+            &BubbleOptions::default(),
+        );
+        assert!(lines[1].contains("short"));
+        assert!(lines[2].contains("a much longer second line"));
+        let border_width = "a much longer second line".len() + 2;
+        assert_eq!(lines[0], format!(" {}", "_".repeat(border_width)));
+        assert_eq!(lines[3], format!(" {}", "-".repeat(border_width)));
+    }
+
+    #[test]
+    fn escaped_newlines_split_text_and_pack_messages() {
+        let cli = Cli::parse_from(["leftysay", "--text", "short\\na much longer second line"]);
+        let text = resolve_message(&cli, None, "hi", None).unwrap();
+        assert_eq!(text, "short\na much longer second line");
+        let messages = parse_messages("one\\ntwo\n");
+        assert_eq!(messages[DEFAULT_CATEGORY], vec!["one\ntwo"]);
+
+        let lines = render_bubble(&text, 40, &BubbleOptions::default());
+        assert_eq!(lines.len(), 4 + 3);
+        assert!(lines[1].contains("short"));
+        assert!(lines[2].contains("a much longer second line"));
+    }
+
+    #[test]
+    fn cli_overrides_are_folded_into_the_dumped_config() {
+        let cli = Cli::parse_from([
+            "leftysay",
+            "--pack",
+            "mine",
+            "--format",
+            "kitty",
+            "--no-cache",
+            "--max-height-ratio",
+            "0.4",
+            "--quiet",
+            "--timeout",
+            "0",
+        ]);
+        let mut config = Config::default();
+        apply_cli_overrides(&mut config, &cli);
+        assert_eq!(config.default_pack, "mine");
+        assert_eq!(config.chafa_timeout_ms, DEFAULT_CHAFA_TIMEOUT_MS);
+        assert_eq!(config.format, ChafaFormat::Kitty);
+        assert!(!config.cache);
+        assert!(config.quiet);
+
+        let mut out = Vec::new();
+        dump_config(&mut out, &config).unwrap();
+        let dumped = String::from_utf8(out).unwrap();
+        assert!(dumped.contains("\nmax_height_ratio = 0.4\n"));
+        assert!(dumped.contains("\nformat = \"kitty\"\n"));
+        let reloaded: Config = toml::from_str(&dumped).unwrap();
+        assert_eq!(reloaded.default_pack, "mine");
+        assert_eq!(reloaded.max_height_ratio, 0.4);
+    }
+
+    #[test]
+    fn messages_parse_into_categories() {
+        let plain = parse_messages("hello\n\nworld\n");
+        assert_eq!(plain.len(), 1);
+        assert_eq!(plain[DEFAULT_CATEGORY], vec!["hello", "world"]);
+
+        let sectioned = parse_messages("hi\n[greetings]\nhey there\n[jokes]\nknock knock\n");
+        assert_eq!(sectioned[DEFAULT_CATEGORY], vec!["hi"]);
+        assert_eq!(sectioned["greetings"], vec!["hey there"]);
+        assert_eq!(sectioned["jokes"], vec!["knock knock"]);
+    }
+
+    #[test]
+    fn bracketed_lines_that_are_not_headers_stay_messages() {
+        let messages = parse_messages("[citation needed]\n[1/2] loading\n[ ]\n[wip]\nok\n");
+        assert_eq!(
+            messages[DEFAULT_CATEGORY],
+            vec!["[citation needed]", "[1/2] loading", "[ ]"]
+        );
+        assert_eq!(messages["wip"], vec!["ok"]);
+    }
+
+    #[test]
+    fn bubble_centers_over_image() {
+        let mut bubble = render_bubble("hi", 80, &BubbleOptions::default());
+        let image = format!("\x1b[31m{}\x1b[0m\n", "#".repeat(40));
+        assert_eq!(visible_width(image.trim_end()), 40);
+
+        let tip_before = visible_width(bubble.last().unwrap()) - 1;
+        center_bubble(&mut bubble, &image, 0, 80);
+        let tip_after = visible_width(bubble.last().unwrap()) - 1;
+        assert!(tip_after > tip_before);
+        assert_eq!(tip_after, 20);
+
+        let indent = image_indent(&image, 80);
+        assert_eq!(indent, 20);
+        let centered = indent_lines(&image, indent);
+        assert!(centered.starts_with(&format!("{}\x1b[31m", " ".repeat(20))));
+        assert_eq!(visible_width(centered.trim_end()), 60);
+        let mut bubble = render_bubble("hi", 80, &BubbleOptions::default());
+        center_bubble(&mut bubble, &image, indent, 80);
+        assert_eq!(visible_width(bubble.last().unwrap()) - 1, 40);
+    }
+
+    #[test]
+    fn vcenter_counts_the_rows_a_graphics_image_covers() {
+        // A 4-line bubble over a 6-row symbols image fills 10 of 30 rows.
+        let image = "#\n".repeat(6);
+        let composed = format!("{}{image}", "b\n".repeat(4));
+        assert_eq!(
+            vcenter_padding(composed.as_bytes(), &image, true, 6, 30),
+            10
+        );
+
+        // A kitty image is a single line of output covering its 6 rows.
+        let image = "\x1b_Gf=100;AAAA\x1b\\\n";
+        let composed = format!("{}{image}", "b\n".repeat(4));
+        assert_eq!(
+            vcenter_padding(composed.as_bytes(), image, false, 6, 30),
+            10
+        );
+        assert_eq!(vcenter_padding(composed.as_bytes(), "", false, 6, 30), 12);
+        assert_eq!(
+            vcenter_padding(composed.as_bytes(), image, false, 40, 30),
+            0
+        );
+    }
+
+    #[test]
+    fn detects_terminal_protocol_from_env() {
+        let detect = |vars: &[(&str, &str)]| {
+            let vars: Vec<(String, String)> = vars
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect();
+            detect_protocol(|name| vars.iter().find(|(k, _)| k == name).map(|(_, v)| v.clone()))
+                .format
+        };
+        assert_eq!(detect(&[("KITTY_WINDOW_ID", "1")]), ChafaFormat::Kitty);
+        assert_eq!(detect(&[("TERM", "xterm-kitty")]), ChafaFormat::Kitty);
+        assert_eq!(
+            detect(&[("TERM_PROGRAM", "iTerm.app")]),
+            ChafaFormat::Iterm2
+        );
+        assert_eq!(detect(&[("TERM", "foot")]), ChafaFormat::Sixel);
+        assert_eq!(detect(&[("TERM", "xterm-256color")]), ChafaFormat::Unicode);
+        assert_eq!(detect(&[]), ChafaFormat::Unicode);
+    }
+
+    #[test]
+    fn chafa_version_parses_first_line() {
+        assert_eq!(parse_version("Chafa version 1.14.0"), Some((1, 14, 0)));
+        assert_eq!(parse_version("chafa 1.8"), Some((1, 8, 0)));
+        assert_eq!(parse_version("Chafa version unknown"), None);
+        assert!(
+            parse_version("Chafa version 1.6.2").unwrap()
+                < min_chafa_version(ChafaFormat::Kitty).unwrap()
+        );
+    }
+
+    #[test]
+    fn parses_hex_background_color() {
+        assert_eq!(parse_hex_color("#28282A").unwrap(), "#28282a");
+        assert!(parse_hex_color("#2828").is_err());
+        assert!(parse_hex_color("#gggggg").is_err());
+    }
+
+    fn parse_config(contents: &str, profile: Option<&str>) -> Result<Config> {
+        config_from_table(toml::from_str(contents)?, profile)
+    }
+
+    #[test]
+    fn config_profiles_override_top_level() {
+        let contents = "bubble_padding = 2\ncache = false\n\
+            [bubble.chars]\ntop = \"=\"\nleft = \"!\"\n\
+            [profiles.work]\nbubble_padding = 0\n[profiles.work.bubble.chars]\ntop = \"~\"\n";
+
+        let base = parse_config(contents, None).unwrap();
+        assert_eq!(base.bubble_padding, 2);
+        assert_eq!(base.bubble.chars.top.as_deref(), Some("="));
+
+        let work = parse_config(contents, Some("work")).unwrap();
+        assert_eq!(work.bubble_padding, 0);
+        assert!(!work.cache);
+        assert_eq!(work.bubble.chars.top.as_deref(), Some("~"));
+        assert_eq!(work.bubble.chars.left.as_deref(), Some("!"));
+
+        let err = parse_config(contents, Some("home")).unwrap_err();
+        assert!(err.to_string().contains("available: work"));
+    }
+
+    #[test]
+    fn system_config_is_merged_under_user_config() {
+        let dir = TempDir::new().unwrap();
+        let system = dir.path().join("system.toml");
+        let user = dir.path().join("user.toml");
+        fs::write(
+            &system,
+            "cache = false\nbubble_padding = 3\n[bubble.chars]\ntop = \"=\"\nleft = \"!\"\n",
+        )
+        .unwrap();
+        fs::write(&user, "bubble_padding = 0\n[bubble.chars]\ntop = \"~\"\n").unwrap();
+
+        let config = load_config_files(&[system.clone(), user.clone()], None).unwrap();
+        assert!(!config.cache);
+        assert_eq!(config.bubble_padding, 0);
+        assert_eq!(config.bubble.chars.top.as_deref(), Some("~"));
+        assert_eq!(config.bubble.chars.left.as_deref(), Some("!"));
+        assert_eq!(config.files, vec![system, user]);
+    }
+
+    #[test]
+    fn config_accepts_chafa_aliases() {
+        for (format, colors) in [("symbols", "full"), ("unicode", "truecolor")] {
+            let config: Config =
+                toml::from_str(&format!("format = \"{format}\"\ncolors = \"{colors}\"\n")).unwrap();
+            assert_eq!(config.format, ChafaFormat::Unicode);
+            assert_eq!(config.colors, ChafaColors::Truecolor);
+        }
+        let json = serde_json::to_value(Config::default()).unwrap();
+        assert_eq!(json["format"], "auto");
+    }
+
+    #[test]
+    fn cache_key_changes_with_size() {
+        let dir = TempDir::new().unwrap();
+        let image_path = dir.path().join("image.png");
+        fs::write(&image_path, b"fake").unwrap();
+
+        let key_small = cache_key(
+            &ImageSource::Path(image_path.clone()),
+            &test_options(40, 10),
+        )
+        .unwrap();
+        let key_large = cache_key(
+            &ImageSource::Path(image_path.clone()),
+            &test_options(80, 10),
+        )
+        .unwrap();
+
+        assert_ne!(key_small, key_large);
+    }
+
+    #[test]
+    fn cache_key_changes_with_sizing() {
+        let dir = TempDir::new().unwrap();
+        let image_path = dir.path().join("image.png");
+        fs::write(&image_path, b"fake").unwrap();
+
+        let sized = |sizing: Sizing| {
+            cache_key(
+                &ImageSource::Path(image_path.clone()),
+                &RenderOptions {
+                    sizing,
+                    ..test_options(40, 10)
+                },
+            )
+            .unwrap()
+        };
+        assert_ne!(sized(Sizing::Stretch), sized(Sizing::Fit));
+        assert_ne!(sized(Sizing::Fill), sized(Sizing::Fit));
+        assert_ne!(sized(Sizing::Stretch), sized(Sizing::Fill));
+    }
+
+    #[test]
+    fn cache_key_hashes_stdin_bytes() {
+        let dir = TempDir::new().unwrap();
+        let image_path = dir.path().join("image.png");
+        fs::write(&image_path, b"fake").unwrap();
+
+        let bytes = |data: &[u8]| {
+            cache_key(&ImageSource::Bytes(data.into()), &test_options(40, 10)).unwrap()
+        };
+        assert_eq!(bytes(b"fake"), bytes(b"fake"));
+        assert_ne!(bytes(b"fake"), bytes(b"other"));
+        let from_path = cache_key(&ImageSource::Path(image_path), &test_options(40, 10)).unwrap();
+        assert_ne!(bytes(b"fake"), from_path);
+    }
+
+    #[test]
+    fn cache_key_changes_with_extra_args() {
+        let dir = TempDir::new().unwrap();
+        let image_path = dir.path().join("image.png");
+        fs::write(&image_path, b"fake").unwrap();
+
+        let dither = vec!["--dither".to_string(), "ordered".to_string()];
+        let plain = cache_key(
+            &ImageSource::Path(image_path.clone()),
+            &test_options(40, 10),
+        )
+        .unwrap();
+        let tuned = cache_key(
+            &ImageSource::Path(image_path.clone()),
+            &RenderOptions {
+                extra_args: &dither,
+                ..test_options(40, 10)
+            },
+        )
+        .unwrap();
+
+        assert_ne!(plain, tuned);
+
+        let dithered = cache_key(
+            &ImageSource::Path(image_path.clone()),
+            &RenderOptions {
+                dither: Some(Dither::Ordered),
+                ..test_options(40, 10)
+            },
+        )
+        .unwrap();
+        assert_ne!(plain, dithered);
+        assert!(parse_dither_grain("4x2").is_ok());
+        assert!(parse_dither_grain("8").is_ok());
+        assert!(parse_dither_grain("3x2").is_err());
+
+        let braille = cache_key(
+            &ImageSource::Path(image_path.clone()),
+            &RenderOptions {
+                symbols: Some(SymbolSet::Braille),
+                ..test_options(40, 10)
+            },
+        )
+        .unwrap();
+        assert_ne!(plain, braille);
+    }
+
+    #[test]
+    fn cache_key_changes_with_chafa_version() {
+        let dir = TempDir::new().unwrap();
+        let image_path = dir.path().join("image.png");
+        fs::write(&image_path, b"fake").unwrap();
+        let chafa = Path::new("/usr/bin/chafa");
+
+        let old_id = identify_chafa(chafa, Some("Chafa version 1.12.4".to_string()));
+        let new_id = identify_chafa(chafa, Some("Chafa version 1.14.0".to_string()));
+        let key = |chafa_id: &str| {
+            cache_key(
+                &ImageSource::Path(image_path.clone()),
+                &RenderOptions {
+                    chafa_id,
+                    ..test_options(40, 10)
+                },
+            )
+            .unwrap()
+        };
+        assert_ne!(key(&old_id), key(&new_id));
+
+        // Without a version the binary's path and mtime are used.
+        let script = dir.path().join("chafa");
+        fs::write(&script, b"#!/bin/sh\n").unwrap();
+        let fallback = identify_chafa(&script, None);
+        assert!(fallback.starts_with(&format!("{}@", script.display())));
+        assert_ne!(fallback, format!("{}@0", script.display()));
+        assert_ne!(key(&fallback), key(&old_id));
+    }
+
+    #[test]
+    fn theme_is_detected_and_cached_separately() {
+        let detect = |theme: Theme, vars: &[(&str, &str)]| {
+            let vars: Vec<(String, String)> = vars
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect();
+            resolve_theme(theme, |name| {
+                vars.iter().find(|(k, _)| k == name).map(|(_, v)| v.clone())
+            })
+        };
+        assert_eq!(detect(Theme::Auto, &[("COLORFGBG", "0;15")]), Theme::Light);
+        assert_eq!(detect(Theme::Auto, &[("COLORFGBG", "15;0")]), Theme::Dark);
+        assert_eq!(
+            detect(
+                Theme::Auto,
+                &[("COLORFGBG", "0;15"), ("LEFTYSAY_THEME", "dark")]
+            ),
+            Theme::Dark
+        );
+        assert_eq!(detect(Theme::Light, &[]), Theme::Light);
+        assert_eq!(detect(Theme::Auto, &[]), Theme::Dark);
+
+        // Only a chosen theme or background is passed to chafa.
+        let dark = Config {
+            theme: Theme::Dark,
+            ..Config::default()
+        };
+        assert_eq!(background_color(&dark).as_deref(), Some("black"));
+        let custom = Config {
+            bg: Some("#282828".to_string()),
+            ..dark
+        };
+        assert_eq!(background_color(&custom).as_deref(), Some("#282828"));
+        assert!(!chafa_args(Path::new("a.png"), &test_options(40, 10)).contains(&"--bg".into()));
+
+        let dir = TempDir::new().unwrap();
+        let image_path = dir.path().join("image.png");
+        fs::write(&image_path, b"fake").unwrap();
+        let light = RenderOptions {
+            bg: Some(Theme::Light.bg_color()),
+            ..test_options(40, 10)
+        };
+        assert_ne!(
+            cache_key(&ImageSource::Path(image_path.clone()), &light).unwrap(),
+            cache_key(
+                &ImageSource::Path(image_path.clone()),
+                &test_options(40, 10)
+            )
+            .unwrap()
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn streamed_images_are_fed_to_chafa_on_stdin() {
+        use std::os::unix::fs::PermissionsExt;
+
+        // Echoes stdin back when chafa is told to read the image from `-`.
+        let dir = TempDir::new().unwrap();
+        let chafa = dir.path().join("chafa");
+        fs::write(&chafa, "#!/bin/sh\n[ \"$1\" = - ] && exec cat\nexit 1\n").unwrap();
+        fs::set_permissions(&chafa, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let image = ImageSource::Bytes(b"not really a png".as_slice().into());
+        let output = run_chafa_once(&chafa, &image, &test_options(40, 10)).unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout, b"not really a png");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn bench_reports_each_format_without_fallbacks() {
+        let dir = TempDir::new().unwrap();
+        let script = dir.path().join("chafa.sh");
+        fs::write(
+            &script,
+            "case \"$2\" in symbols) echo ok ;; *) echo \"no $2\" >&2; exit 1 ;; esac\n",
+        )
+        .unwrap();
+        let config = Config {
+            format_fallbacks: vec![ChafaFormat::Unicode],
+            ..Config::default()
+        };
+
+        let mut out = Vec::new();
+        run_bench(&mut out, Path::new("/bin/sh"), &config, Some(&script)).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let row = |format: &str, colors: &str| {
+            out.lines()
+                .find(|line| line.split_whitespace().take(2).eq([format, colors]))
+                .unwrap()
+                .split_whitespace()
+                .skip(3)
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+        assert_eq!(row("symbols", "full"), "3");
+        assert_eq!(row("kitty", "full"), "failed no kitty");
+        assert_eq!(row("sixels", "none"), "failed no sixels");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn format_fallbacks_are_tried_in_order() {
+        // chafa's first argument is the image, so `sh <script>` stands in for
+        // a chafa that only supports symbols.
+        let dir = TempDir::new().unwrap();
+        let script = dir.path().join("chafa.sh");
+        fs::write(
+            &script,
+            "case \"$2\" in symbols) echo \"ok $4\" ;; *) echo \"no $2\" >&2; exit 1 ;; esac\n",
+        )
+        .unwrap();
+        let image = ImageSource::Path(script);
+        let sh = Path::new("/bin/sh");
+        let options = |fallbacks| RenderOptions {
+            format: ChafaFormat::Kitty,
+            format_fallbacks: fallbacks,
+            ..test_options(40, 10)
+        };
+
+        let chain = [ChafaFormat::Sixel, ChafaFormat::Unicode];
+        assert_eq!(
+            run_chafa(sh, &image, &options(&chain)).unwrap(),
+            ("ok full\n".to_string(), true)
+        );
+
+        // The fallback's output must not be cached for kitty.
+        let cache = TempDir::new().unwrap();
+        let cached = RenderOptions {
+            cache_enabled: true,
+            cache_dir: cache.path(),
+            ..options(&chain)
+        };
+        let rendered = render_image(sh, &image, cached).unwrap();
+        assert_eq!(rendered.text, "ok full\n");
+        assert!(!cache_path(cache.path(), &image, &cached).unwrap().exists());
+
+        let err = run_chafa(sh, &image, &options(&[ChafaFormat::Sixel]))
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            err,
+            "chafa failed:\nkitty/auto: no kitty\nsixels/full: no sixels"
+        );
+    }
+
+    #[test]
+    fn dry_run_command_is_shell_quoted() {
+        let extra = vec!["--dither-diffusion".to_string(), "0.5 it's".to_string()];
+        let options = RenderOptions {
+            format: ChafaFormat::Unicode,
+            symbols: Some(SymbolSet::Block),
+            extra_args: &extra,
+            ..test_options(40, 10)
+        };
+        let command = shell_command(
+            Path::new("/usr/bin/chafa"),
+            &chafa_args(Path::new("my cat.png"), &options),
+        );
+        assert_eq!(
+            command,
+            "/usr/bin/chafa 'my cat.png' --format symbols --colors auto --size 40x10 \
+             --symbols block --dither-diffusion '0.5 it'\\''s'"
+        );
+    }
+
+    #[test]
+    fn gif_frames_are_extracted_by_index() {
+        let dir = TempDir::new().unwrap();
+        let gif_path = dir.path().join("anim.gif");
+        {
+            let palette = [0, 0, 0, 255, 0, 0, 0, 255, 0];
+            let file = fs::File::create(&gif_path).unwrap();
+            let mut encoder = gif::Encoder::new(file, 2, 1, &palette).unwrap();
+            for color in [1u8, 2] {
+                let frame = gif::Frame {
+                    width: 2,
+                    height: 1,
+                    buffer: std::borrow::Cow::Owned(vec![color, color]),
+                    ..gif::Frame::default()
+                };
+                encoder.write_frame(&frame).unwrap();
+            }
+        }
+
+        let decode = |png: Vec<u8>| {
+            let mut reader = png::Decoder::new(std::io::Cursor::new(png))
+                .read_info()
+                .unwrap();
+            let mut pixels = vec![0; reader.output_buffer_size().unwrap()];
+            reader.next_frame(&mut pixels).unwrap();
+            pixels[..4].to_vec()
+        };
+        assert_eq!(
+            decode(extract_gif_frame(&gif_path, 0).unwrap()),
+            [255, 0, 0, 255]
+        );
+        assert_eq!(
+            decode(extract_gif_frame(&gif_path, 1).unwrap()),
+            [0, 255, 0, 255]
+        );
+        assert!(extract_gif_frame(&gif_path, 2).is_err());
+        assert_eq!(gif_frame_delays(&gif_path).unwrap().len(), 2);
+
+        let cache = TempDir::new().unwrap();
+        let frame_path = extract_gif_frame_to_cache(&gif_path, 1, cache.path()).unwrap();
+        assert_eq!(frame_path, gif_frame_path(&gif_path, 1, cache.path()));
+        let entries: Vec<_> = fs::read_dir(cache.path())
+            .unwrap()
+            .filter_map(Result::ok)
+            .filter(is_cache_entry)
+            .collect();
+        assert_eq!(entries.len(), 1);
+
+        let first = cache_key(&ImageSource::Path(gif_path.clone()), &test_options(40, 10)).unwrap();
+        let second = cache_key(
+            &ImageSource::Path(gif_path.clone()),
+            &RenderOptions {
+                frame: 1,
+                ..test_options(40, 10)
+            },
+        )
+        .unwrap();
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn bubble_is_shown_while_rendering_and_erased_on_failure() {
+        // Flushing the bubble is what lets the render finish.
+        struct Signal(Vec<u8>, std::sync::mpsc::Sender<()>);
+        impl Write for Signal {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.write(buf)
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                let _ = self.1.send(());
+                Ok(())
+            }
+        }
+        let image = |text: &str| RenderedImage {
+            text: text.to_string(),
+            cache_hit: false,
+            elapsed: Duration::ZERO,
+        };
 
-    if total_size <= max_bytes {
-        return Ok(());
-    }
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let mut out = Signal(Vec::new(), sender);
+        let rendered = render_behind_bubble(&mut out, b"< hi >\n  \\\n", move || {
+            receiver
+                .recv_timeout(Duration::from_secs(10))
+                .map_err(|_| anyhow!("the bubble was not printed first"))?;
+            Ok(image("IMG\n"))
+        })
+        .unwrap()
+        .unwrap();
+        assert_eq!(rendered.text, "IMG\n");
+        assert_eq!(out.0, b"< hi >\n  \\\n");
 
-    entries.sort_by_key(|entry| entry.metadata().and_then(|m| m.modified()).ok());
+        let mut out = Vec::new();
+        let rendered =
+            render_behind_bubble(&mut out, b"< hi >\n  \\\n", || Err(anyhow!("chafa failed")))
+                .unwrap();
+        assert!(rendered.is_err());
+        assert_eq!(out, b"< hi >\n  \\\n\x1b[2A\x1b[J");
+    }
 
-    for entry in entries {
-        if total_size <= max_bytes {
-            break;
-        }
-        let meta = entry.metadata().ok();
-        if let Ok(()) = fs::remove_file(entry.path()) {
-            if let Some(len) = meta.map(|m| m.len()) {
-                total_size = total_size.saturating_sub(len);
+    #[test]
+    fn loops_become_a_chafa_duration() {
+        let dir = TempDir::new().unwrap();
+        let gif_path = dir.path().join("anim.gif");
+        {
+            let file = fs::File::create(&gif_path).unwrap();
+            let mut encoder = gif::Encoder::new(file, 1, 1, &[0, 0, 0]).unwrap();
+            for delay in [5, 0] {
+                let frame = gif::Frame {
+                    width: 1,
+                    height: 1,
+                    delay,
+                    buffer: std::borrow::Cow::Owned(vec![0]),
+                    ..gif::Frame::default()
+                };
+                encoder.write_frame(&frame).unwrap();
             }
         }
+
+        assert_eq!(loops_duration_ms(&gif_path, 0, 1), None);
+        assert_eq!(loops_duration_ms(&gif_path, 0, 3), Some(450));
+        assert_eq!(loops_duration_ms(&gif_path, 4, 3), Some(1500));
+
+        let options = RenderOptions {
+            animate: true,
+            duration_ms: Some(450),
+            ..test_options(40, 10)
+        };
+        let args = chafa_args(&gif_path, &options);
+        assert!(args.windows(2).any(|pair| pair == ["--duration", "0.450"]));
+        let source = ImageSource::Path(gif_path);
+        assert_ne!(
+            cache_key(&source, &options).unwrap(),
+            cache_key(
+                &source,
+                &RenderOptions {
+                    duration_ms: None,
+                    ..options
+                }
+            )
+            .unwrap()
+        );
     }
 
-    Ok(())
-}
+    #[test]
+    fn list_details_read_image_headers() {
+        let dir = TempDir::new().unwrap();
+        let gif_path = dir.path().join("anim.gif");
+        {
+            let file = fs::File::create(&gif_path).unwrap();
+            let mut encoder = gif::Encoder::new(file, 2, 1, &[0, 0, 0, 255, 0, 0]).unwrap();
+            let frame = gif::Frame {
+                width: 2,
+                height: 1,
+                buffer: std::borrow::Cow::Owned(vec![1, 1]),
+                ..gif::Frame::default()
+            };
+            encoder.write_frame(&frame).unwrap();
+        }
+        let png_path = dir.path().join("frame.png");
+        fs::write(&png_path, extract_gif_frame(&gif_path, 0).unwrap()).unwrap();
+        let jpeg_path = dir.path().join("photo.jpg");
+        let mut jpeg = vec![0xff, 0xd8, 0xff, 0xe0, 0x00, 0x10];
+        jpeg.extend([0; 14]);
+        jpeg.extend([0xff, 0xc0, 0x00, 0x11, 0x08, 0x00, 0x10, 0x00, 0x20, 0x03]);
+        fs::write(&jpeg_path, jpeg).unwrap();
+        let bmp_path = dir.path().join("old.bmp");
+        let mut bmp = b"BM".to_vec();
+        bmp.resize(18, 0);
+        bmp.extend(5i32.to_le_bytes());
+        bmp.extend(3i32.to_le_bytes());
+        fs::write(&bmp_path, bmp).unwrap();
+        let broken_path = dir.path().join("broken.png");
+        fs::write(&broken_path, b"fake").unwrap();
 
-fn print_pack_list(packs: &[Pack]) {
-    if packs.is_empty() {
-        println!("No packs found.");
-        return;
+        assert_eq!(image_dimensions(&gif_path), Some((2, 1)));
+        assert_eq!(image_dimensions(&png_path), Some((2, 1)));
+        assert_eq!(image_dimensions(&jpeg_path), Some((32, 16)));
+        assert_eq!(image_dimensions(&bmp_path), Some((5, 3)));
+        assert_eq!(image_dimensions(&broken_path), None);
+
+        let mut pack = test_pack("details");
+        pack.images = vec![jpeg_path, broken_path];
+        let mut out = Vec::new();
+        print_pack_list(&mut out, &[pack], true).unwrap();
+        let listing = String::from_utf8(out).unwrap();
+        assert!(listing.contains("  - photo.jpg  32x16  30 B\n"));
+        assert!(listing.contains("  - broken.png  ?x?  4 B\n"));
     }
-    for pack in packs {
-        println!(
-            "{} (v{}, {}): {}",
-            pack.meta.name, pack.meta.version, pack.meta.license, pack.meta.description
-        );
-        for image in &pack.images {
-            if let Some(name) = image.file_name().and_then(OsStr::to_str) {
-                println!("  - {name}");
-            }
+
+    #[test]
+    fn cache_limit_evicts_by_entry_count() {
+        let dir = TempDir::new().unwrap();
+        for i in 0..5 {
+            fs::write(dir.path().join(format!("{}.txt", entry_key(i))), b"x").unwrap();
         }
+        fs::write(dir.path().join("notes.txt"), b"mine").unwrap();
+
+        let entries = || {
+            fs::read_dir(dir.path())
+                .unwrap()
+                .filter_map(Result::ok)
+                .filter(is_cache_entry)
+                .count()
+        };
+        enforce_cache_limit(dir.path(), None, u64::MAX, 2).unwrap();
+        assert_eq!(entries(), 2);
+
+        enforce_cache_limit(dir.path(), None, u64::MAX, 0).unwrap();
+        assert_eq!(entries(), 2);
+        assert!(dir.path().join("notes.txt").exists());
     }
-}
 
-#[derive(Clone, Copy, Debug)]
-struct RenderOptions {
-    cols: usize,
-    rows: usize,
-    format: ChafaFormat,
-    colors: ChafaColors,
-    animate: bool,
-    cache_enabled: bool,
-    cache_max_mb: u64,
-}
+    fn entry_key(i: usize) -> String {
+        blake3::hash(i.to_string().as_bytes()).to_hex().to_string()
+    }
 
-fn print_doctor(chafa: &Path, cols: usize, rows: usize, config: &Config) -> Result<()> {
-    println!("leftysay doctor");
-    println!("chafa: {}", chafa.display());
-    println!("terminal: {} cols x {} rows", cols, rows);
-    println!("config.format: {}", config.format.as_arg());
-    println!("config.colors: {}", config.colors.as_arg());
-    println!("config.max_height_ratio: {}", config.max_height_ratio);
-    println!("config.cache: {}", config.cache);
-    println!("config.cache_max_mb: {}", config.cache_max_mb);
+    #[test]
+    fn cache_eviction_follows_index_and_rebuilds_it() {
+        let dir = TempDir::new().unwrap();
+        let mut index = CacheIndex::default();
+        for (i, last_used_ms) in [(0, 3), (1, 1), (2, 2)] {
+            let file = format!("{}.{CACHE_FILE_EXT}", entry_key(i));
+            fs::write(dir.path().join(&file), b"x").unwrap();
+            let entry = CacheIndexEntry {
+                size: 1,
+                last_used_ms,
+            };
+            index.entries.insert(file, entry);
+        }
+        save_cache_index(dir.path(), &index).unwrap();
 
-    if let Some(proj_dirs) = ProjectDirs::from("", "", "leftysay") {
-        println!("config dir: {}", proj_dirs.config_dir().display());
-        println!("data dir: {}", proj_dirs.data_dir().display());
-        println!("cache dir: {}", proj_dirs.cache_dir().display());
+        enforce_cache_limit(dir.path(), None, u64::MAX, 2).unwrap();
+        assert!(!dir
+            .path()
+            .join(format!("{}.{CACHE_FILE_EXT}", entry_key(1)))
+            .exists());
+        assert!(dir
+            .path()
+            .join(format!("{}.{CACHE_FILE_EXT}", entry_key(2)))
+            .exists());
+
+        fs::write(dir.path().join(CACHE_INDEX_FILE), b"not json").unwrap();
+        enforce_cache_limit(dir.path(), None, u64::MAX, 1).unwrap();
+        assert_eq!(load_cache_index(dir.path()).entries.len(), 1);
     }
-    println!("pack search paths:");
-    for path in pack_search_paths() {
-        println!("  - {}", path.display());
+
+    #[test]
+    fn cache_hits_are_batched_and_unknown_entries_counted() {
+        let dir = TempDir::new().unwrap();
+        let path = |i| {
+            dir.path()
+                .join(format!("{}.{CACHE_FILE_EXT}", entry_key(i)))
+        };
+        let mut index = CacheIndex::default();
+        for i in 0..2 {
+            fs::write(path(i), b"x").unwrap();
+            let entry = CacheIndexEntry {
+                size: 1,
+                last_used_ms: i as u64 + 1,
+            };
+            index
+                .entries
+                .insert(cache_entry_name(&path(i)).unwrap(), entry);
+        }
+        save_cache_index(dir.path(), &index).unwrap();
+
+        let stored = fs::read(dir.path().join(CACHE_INDEX_FILE)).unwrap();
+        touch_cache_entry(&path(0)).unwrap();
+        assert_eq!(fs::read(dir.path().join(CACHE_INDEX_FILE)).unwrap(), stored);
+        assert!(dir.path().join(CACHE_HITS_FILE).exists());
+        assert_eq!(
+            load_cache_index(dir.path()).entries[&cache_entry_name(&path(0)).unwrap()].size,
+            1
+        );
+
+        // Written by someone else and never recorded in the index.
+        fs::write(path(2), b"x").unwrap();
+        enforce_cache_limit(dir.path(), None, u64::MAX, 2).unwrap();
+        assert_ne!(fs::read(dir.path().join(CACHE_INDEX_FILE)).unwrap(), stored);
+        assert!(!dir.path().join(CACHE_HITS_FILE).exists());
+        assert!(path(0).exists());
+        assert!(!path(1).exists());
+        assert_eq!(load_cache_index(dir.path()).entries.len(), 2);
     }
 
-    Ok(())
-}
+    #[test]
+    fn cache_files_are_compressed_and_migrated() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join(format!("entry.{CACHE_FILE_EXT}"));
+        let legacy = dir.path().join("entry.txt");
+        fs::write(&legacy, "plain render").unwrap();
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use tempfile::TempDir;
+        assert_eq!(read_cache_file(&path).unwrap(), "plain render");
+        assert!(!legacy.exists());
+
+        write_cache_file(&path, "plain render").unwrap();
+        assert_ne!(fs::read(&path).unwrap(), b"plain render");
+        assert_eq!(read_cache_file(&path).unwrap(), "plain render");
+    }
 
     #[test]
-    fn bubble_renders_multiple_lines() {
-        let lines = render_bubble("hello\tworld from leftysay", 40);
-        assert!(lines.len() >= 3);
-        assert!(lines.first().unwrap().contains('_'));
-        assert!(lines.iter().any(|line| line.contains('-')));
-        assert!(lines.iter().any(|line| line.trim().starts_with('o')));
+    fn concurrent_cache_writes_and_eviction_do_not_fail() {
+        let dir = TempDir::new().unwrap();
+        ensure_cache_version(dir.path()).unwrap();
+        let threads: Vec<_> = (0..8)
+            .map(|t| {
+                let dir = dir.path().to_path_buf();
+                thread::spawn(move || {
+                    for i in 0..50 {
+                        let name = format!("{}.{CACHE_FILE_EXT}", entry_key((t + i) % 12));
+                        let path = dir.join(name);
+                        let contents = format!("render {}", (t + i) % 12);
+                        write_cache_file(&path, &contents).unwrap();
+                        if let Some(read) = read_cache_file(&path) {
+                            assert_eq!(read, contents);
+                        }
+                        enforce_cache_limit(&dir, Some(&path), u64::MAX, 4).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for handle in threads {
+            handle.join().unwrap();
+        }
+
+        let leftovers: Vec<_> = fs::read_dir(dir.path())
+            .unwrap()
+            .filter_map(Result::ok)
+            .filter(|entry| entry.path().extension() == Some(OsStr::new("tmp")))
+            .collect();
+        assert!(leftovers.is_empty());
     }
 
     #[test]
-    fn cache_key_changes_with_size() {
+    fn cache_dir_prefers_env_then_config_then_default() {
+        let mut config = Config {
+            cache_dir: Some(PathBuf::from("/tmp/from-config")),
+            ..Config::default()
+        };
+        assert_eq!(
+            resolve_cache_dir_with(Some(OsStr::new("/tmp/from-env")), &config),
+            (PathBuf::from("/tmp/from-env"), "LEFTYSAY_CACHE_DIR")
+        );
+        assert_eq!(
+            resolve_cache_dir_with(Some(OsStr::new("")), &config),
+            (PathBuf::from("/tmp/from-config"), "config")
+        );
+        assert_eq!(
+            resolve_cache_dir_with(None, &config),
+            (PathBuf::from("/tmp/from-config"), "config")
+        );
+        config.cache_dir = None;
+        assert_eq!(resolve_cache_dir_with(None, &config).1, "default");
+
+        if let Some(dirs) = BaseDirs::new() {
+            config.cache_dir = Some(PathBuf::from("~/renders"));
+            assert_eq!(
+                resolve_cache_dir_with(None, &config).0,
+                dirs.home_dir().join("renders")
+            );
+        }
+    }
+
+    #[test]
+    fn cache_is_wiped_when_schema_version_changes() {
         let dir = TempDir::new().unwrap();
-        let image_path = dir.path().join("image.png");
-        fs::write(&image_path, b"fake").unwrap();
+        let entry = dir
+            .path()
+            .join(format!("{}.{CACHE_FILE_EXT}", entry_key(0)));
+        fs::write(dir.path().join(CACHE_VERSION_FILE), "0").unwrap();
+        fs::write(&entry, b"stale").unwrap();
+        let unrelated = dir.path().join("notes.txt");
+        fs::write(&unrelated, b"keep").unwrap();
 
-        let key_small = cache_key(
-            &image_path,
-            40,
-            10,
-            ChafaFormat::Auto,
-            ChafaColors::Auto,
-            false,
-        )
-        .unwrap();
-        let key_large = cache_key(
-            &image_path,
-            80,
-            10,
-            ChafaFormat::Auto,
-            ChafaColors::Auto,
-            false,
-        )
-        .unwrap();
+        ensure_cache_version(dir.path()).unwrap();
+        assert!(!entry.exists());
+        assert!(unrelated.exists());
 
-        assert_ne!(key_small, key_large);
+        fs::write(&entry, b"fresh").unwrap();
+        ensure_cache_version(dir.path()).unwrap();
+        assert!(entry.exists());
     }
 
     #[test]
@@ -837,7 +6758,7 @@ mod tests {
         fs::write(pack_root.join("images/test.png"), b"fake").unwrap();
 
         std::env::set_var("LEFTYSAY_PACKS_DIR", dir.path().join("packs"));
-        let packs = scan_packs().unwrap();
+        let packs = scan_packs(&Config::default()).unwrap().packs;
         assert!(packs.iter().any(|pack| pack.meta.name == "default"));
         let pack = packs
             .iter()
@@ -846,4 +6767,586 @@ mod tests {
         assert_eq!(pack.images.len(), 1);
         std::env::remove_var("LEFTYSAY_PACKS_DIR");
     }
+
+    fn write_pack(root: &Path, name: &str, description: &str) {
+        fs::create_dir_all(root.join("images")).unwrap();
+        fs::write(
+            root.join("pack.toml"),
+            format!("name = \"{name}\"\nversion = \"0.1.0\"\nlicense = \"CC0-1.0\"\ndescription = \"{description}\"\nimages_dir = \"images\"\n"),
+        )
+        .unwrap();
+        fs::write(root.join("images/test.png"), b"fake").unwrap();
+    }
+
+    #[test]
+    fn scan_treats_bare_image_folder_as_pack() {
+        let dir = TempDir::new().unwrap();
+        let folder = dir.path().join("doodles");
+        fs::create_dir_all(&folder).unwrap();
+        fs::write(folder.join("one.png"), b"fake").unwrap();
+        fs::write(folder.join("two.png"), b"fake").unwrap();
+
+        let scan = scan_pack_roots(std::slice::from_ref(&folder), &Config::default()).unwrap();
+        assert_eq!(scan.packs.len(), 1);
+        assert_eq!(scan.packs[0].meta.name, "doodles");
+        assert_eq!(scan.packs[0].images.len(), 2);
+
+        let empty = dir.path().join("empty");
+        fs::create_dir_all(&empty).unwrap();
+        assert!(implicit_pack(&empty, &Config::default()).unwrap().is_none());
+    }
+
+    #[test]
+    fn scan_prefers_earlier_search_path_on_name_collision() {
+        let dir = TempDir::new().unwrap();
+        let high = dir.path().join("high");
+        let low = dir.path().join("low");
+        write_pack(&high.join("mine"), "shared", "high priority");
+        write_pack(&low.join("other"), "shared", "low priority");
+
+        for _ in 0..5 {
+            let scan = scan_pack_roots(&[high.clone(), low.clone()], &Config::default()).unwrap();
+            assert_eq!(scan.packs.len(), 1);
+            assert_eq!(scan.packs[0].meta.description, "high priority");
+            assert_eq!(scan.shadowed.len(), 1);
+            assert_eq!(scan.shadowed[0].winner, high.join("mine"));
+            assert_eq!(scan.shadowed[0].shadowed, low.join("other"));
+        }
+    }
+
+    #[test]
+    fn packs_named_like_a_selector_are_reported() {
+        let dir = TempDir::new().unwrap();
+        let base = dir.path().join("packs");
+        write_pack(&base.join("everything"), ALL_PACKS, "Test");
+        write_pack(&base.join("mine"), "mine", "Test");
+
+        let scan = scan_pack_roots(std::slice::from_ref(&base), &Config::default()).unwrap();
+        assert_eq!(scan.packs.len(), 2);
+        assert_eq!(scan.reserved.len(), 1);
+        assert_eq!(scan.reserved[0].root, base.join("everything"));
+        assert!(describe_reserved_pack(&scan.reserved[0]).contains("--pack all"));
+
+        let problems = validate_pack_dir(&base.join("everything"), &Config::default());
+        assert!(problems
+            .iter()
+            .any(|p| p.severity == Severity::Error && p.message.contains("reserved")));
+        assert!(validate_pack_dir(&base.join("mine"), &Config::default()).is_empty());
+
+        let mut out = Vec::new();
+        let seed = (None, SeedSource::Entropy);
+        print_doctor_json(
+            &mut out,
+            Path::new("/nonexistent/chafa"),
+            80,
+            24,
+            &Config::default(),
+            &scan,
+            seed,
+        )
+        .unwrap();
+        let report: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(report["reserved_packs"][0]["name"], ALL_PACKS);
+        assert_eq!(
+            report["reserved_packs"][0]["root"],
+            base.join("everything").to_string_lossy().as_ref()
+        );
+    }
+
+    #[test]
+    fn pack_index_invalidates_when_images_change() {
+        let dir = TempDir::new().unwrap();
+        let base = dir.path().join("packs");
+        write_pack(&base.join("mine"), "mine", "Test");
+        let bases = vec![base.clone()];
+        let config = Config::default();
+        let index_path = dir.path().join(PACK_INDEX_FILE);
+        let key = pack_index_key(&bases, &config);
+
+        let scan = scan_pack_roots(&bases, &config).unwrap();
+        write_pack_index(&index_path, key.clone(), &bases, &scan, &config).unwrap();
+        let cached = read_pack_index(&index_path, &key).unwrap();
+        assert_eq!(cached.packs[0].images.len(), 1);
+        assert!(read_pack_index(&index_path, "other-key").is_none());
+
+        std::thread::sleep(Duration::from_millis(20));
+        fs::write(base.join("mine/images/second.png"), b"fake").unwrap();
+        assert!(read_pack_index(&index_path, &key).is_none());
+
+        // A new pack in a directory that held no pack when the index was written.
+        fs::create_dir_all(base.join("later")).unwrap();
+        let scan = scan_pack_roots(&bases, &config).unwrap();
+        write_pack_index(&index_path, key.clone(), &bases, &scan, &config).unwrap();
+        assert!(read_pack_index(&index_path, &key).is_some());
+        std::thread::sleep(Duration::from_millis(20));
+        write_pack(&base.join("later/two"), "two", "Test");
+        assert!(read_pack_index(&index_path, &key).is_none());
+    }
+
+    #[test]
+    fn collect_images_applies_include_then_exclude() {
+        let dir = TempDir::new().unwrap();
+        let root = dir.path();
+        fs::create_dir_all(root.join("images/sub")).unwrap();
+        for name in ["a.png", "wip_b.png", "sub/c.png", "sub/wip_d.png", "e.jpg"] {
+            fs::write(root.join("images").join(name), b"fake").unwrap();
+        }
+        let mut meta = test_pack("globs").meta;
+        meta.include = vec!["**/*.png".to_string()];
+        meta.exclude = vec!["**/wip_*.png".to_string()];
+
+        let mut names: Vec<String> = collect_images(root, &meta, &[], true)
+            .unwrap()
+            .iter()
+            .map(|p| {
+                p.strip_prefix(root.join("images"))
+                    .unwrap()
+                    .to_string_lossy()
+                    .into_owned()
+            })
+            .collect();
+        names.sort();
+        assert_eq!(names, vec!["a.png", "sub/c.png"]);
+    }
+
+    #[test]
+    fn collect_images_is_sorted_by_relative_path() {
+        let dir = TempDir::new().unwrap();
+        let images = dir.path().join("images");
+        for name in ["zebra.png", "b/cat.png", "apple.png", "b/ant.png", "a.png"] {
+            let path = images.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, b"fake").unwrap();
+        }
+
+        let mut meta = test_pack("sorted").meta;
+        meta.images_dir = "images".to_string();
+        let found: Vec<PathBuf> = collect_images(dir.path(), &meta, &[], true)
+            .unwrap()
+            .into_iter()
+            .map(|path| path.strip_prefix(&images).unwrap().to_path_buf())
+            .collect();
+        let expected: Vec<PathBuf> = ["a.png", "apple.png", "b/ant.png", "b/cat.png", "zebra.png"]
+            .iter()
+            .map(PathBuf::from)
+            .collect();
+        assert_eq!(found, expected);
+    }
+
+    #[test]
+    fn dedup_keeps_the_first_copy_and_falls_back_to_unblocked_copies() {
+        let dir = TempDir::new().unwrap();
+        let base = dir.path().join("packs");
+        write_pack(&base.join("mine"), "mine", "Test");
+        let images = base.join("mine/images");
+        for name in ["b.png", "c.png", "d.png"] {
+            fs::write(images.join(name), b"fake").unwrap();
+        }
+        fs::write(images.join("other.png"), b"other").unwrap();
+        let bases = vec![base.clone()];
+        let config = Config {
+            dedup_images: true,
+            ..Config::default()
+        };
+
+        let scan = scan_pack_roots(&bases, &config).unwrap();
+        let pack = &scan.packs[0];
+        let first = pack.images[0].clone();
+        assert_eq!(pack.images, vec![first.clone(), images.join("other.png")]);
+        assert_eq!(pack.duplicates[&first].len(), 3);
+
+        // Blocking the kept copy falls back to the next one instead of
+        // dropping the artwork.
+        let name = first.file_name().unwrap().to_string_lossy().into_owned();
+        let blocklist = build_globset(&[name]).unwrap();
+        let allowed = unblocked_images(pack, blocklist.as_ref());
+        assert_eq!(allowed.len(), 2);
+        assert_eq!(allowed[0], pack.duplicates[&first][0]);
+
+        // Editing a copy in place changes no directory, but must refresh the index.
+        let index_path = dir.path().join(PACK_INDEX_FILE);
+        let key = pack_index_key(&bases, &config);
+        write_pack_index(&index_path, key.clone(), &bases, &scan, &config).unwrap();
+        assert!(read_pack_index(&index_path, &key).is_some());
+        std::thread::sleep(Duration::from_millis(20));
+        fs::write(images.join("d.png"), b"changed").unwrap();
+        assert!(read_pack_index(&index_path, &key).is_none());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn collect_images_follows_symlinked_dirs() {
+        let library = TempDir::new().unwrap();
+        fs::write(library.path().join("shared.png"), b"fake").unwrap();
+        let dir = TempDir::new().unwrap();
+        fs::create_dir_all(dir.path().join("images")).unwrap();
+        std::os::unix::fs::symlink(library.path(), dir.path().join("images/linked")).unwrap();
+        // A loop back into the images dir must not hang or error the scan.
+        std::os::unix::fs::symlink(dir.path().join("images"), dir.path().join("images/loop"))
+            .unwrap();
+
+        let mut meta = test_pack("linked").meta;
+        meta.images_dir = "images".to_string();
+        let found = collect_images(dir.path(), &meta, &[], true).unwrap();
+        assert_eq!(found, vec![dir.path().join("images/linked/shared.png")]);
+        assert!(collect_images(dir.path(), &meta, &[], false)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn pack_meta_errors_name_the_field() {
+        let missing = parse_pack_meta(
+            "version = \"1\"\nlicense = \"MIT\"\ndescription = \"d\"\nimages_dir = \"images\"\n",
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(missing.contains("missing required field `name`"));
+
+        let mistyped = parse_pack_meta(
+            "name = \"p\"\nversion = 1\nlicense = \"MIT\"\ndescription = \"d\"\nimages_dir = \"images\"\n",
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(mistyped.contains("`version` must be a string, found integer"));
+    }
+
+    #[test]
+    fn new_pack_scaffold_validates_once_images_are_added() {
+        let dir = TempDir::new().unwrap();
+        let pack_root = dir.path().join("cats");
+        let meta = PackMeta {
+            name: "cats".to_string(),
+            version: "0.1.0".to_string(),
+            license: "CC0-1.0".to_string(),
+            description: "Cats \"meow\"".to_string(),
+            images_dir: "images".to_string(),
+            include: Vec::new(),
+            exclude: Vec::new(),
+            speaker: None,
+            attribution_url: None,
+            enabled: true,
+            frames: BTreeMap::new(),
+            format: None,
+            colors: None,
+        };
+        create_pack(&pack_root, &meta, false).unwrap();
+        let written = read_pack_meta(&pack_root.join("pack.toml")).unwrap();
+        assert_eq!(written.description, meta.description);
+        assert_eq!(
+            read_messages(&pack_root)[DEFAULT_CATEGORY],
+            vec!["Hello from cats!"]
+        );
+
+        fs::write(pack_root.join("images/cat.png"), b"fake").unwrap();
+        assert!(validate_pack_dir(&pack_root, &Config::default()).is_empty());
+        assert!(create_pack(&pack_root, &meta, false).is_err());
+        create_pack(&pack_root, &meta, true).unwrap();
+        assert!(pack_root.join("images/cat.png").exists());
+    }
+
+    #[test]
+    fn validate_reports_pack_without_images() {
+        let dir = TempDir::new().unwrap();
+        let pack_root = dir.path();
+        fs::create_dir_all(pack_root.join("images")).unwrap();
+        fs::write(
+            pack_root.join("pack.toml"),
+            "name = \"empty\"\nversion = \"0.1.0\"\nlicense = \"CC0-1.0\"\ndescription = \"Test\"\nimages_dir = \"images\"\n",
+        )
+        .unwrap();
+        fs::write(pack_root.join("images/notes.txt"), b"not an image").unwrap();
+
+        let problems = validate_pack_dir(pack_root, &Config::default());
+        assert!(problems
+            .iter()
+            .any(|p| p.severity == Severity::Error && p.message.contains("no supported images")));
+        assert!(problems
+            .iter()
+            .any(|p| p.severity == Severity::Warning && p.message.contains("notes.txt")));
+
+        fs::write(pack_root.join("images/ok.png"), b"fake").unwrap();
+        let problems = validate_pack_dir(pack_root, &Config::default());
+        assert!(problems.iter().all(|p| p.severity == Severity::Warning));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn validate_honors_follow_symlinks() {
+        let library = TempDir::new().unwrap();
+        fs::write(library.path().join("shared.png"), b"fake").unwrap();
+        let dir = TempDir::new().unwrap();
+        let pack_root = dir.path();
+        fs::create_dir_all(pack_root.join("images")).unwrap();
+        std::os::unix::fs::symlink(library.path(), pack_root.join("images/linked")).unwrap();
+        fs::write(
+            pack_root.join("pack.toml"),
+            "name = \"linked\"\nversion = \"0.1.0\"\nlicense = \"CC0-1.0\"\ndescription = \"Test\"\nimages_dir = \"images\"\n",
+        )
+        .unwrap();
+
+        assert!(validate_pack_dir(pack_root, &Config::default()).is_empty());
+        let config = Config {
+            follow_symlinks: false,
+            ..Config::default()
+        };
+        let problems = validate_pack_dir(pack_root, &config);
+        assert!(problems
+            .iter()
+            .any(|p| p.severity == Severity::Error && p.message.contains("no supported images")));
+    }
+
+    fn test_pack(name: &str) -> Pack {
+        Pack {
+            meta: PackMeta {
+                name: name.to_string(),
+                version: "0.1.0".to_string(),
+                license: "CC0-1.0".to_string(),
+                description: "Test".to_string(),
+                images_dir: "images".to_string(),
+                include: Vec::new(),
+                exclude: Vec::new(),
+                speaker: None,
+                attribution_url: None,
+                enabled: true,
+                frames: BTreeMap::new(),
+                format: None,
+                colors: None,
+            },
+            root: PathBuf::from(name),
+            images: vec![PathBuf::from(format!("{name}.png"))],
+            duplicates: BTreeMap::new(),
+            messages: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn pack_format_only_replaces_auto() {
+        let mut pack = test_pack("pixels");
+        let meta: PackMeta = toml::from_str(
+            "name = \"pixels\"\nversion = \"0.1.0\"\nlicense = \"CC0-1.0\"\n\
+             description = \"Test\"\nimages_dir = \"images\"\n\
+             format = \"symbols\"\ncolors = \"256\"\n",
+        )
+        .unwrap();
+        pack.meta = meta;
+        let meta = Some(&pack.meta);
+
+        assert_eq!(pack_format(ChafaFormat::Auto, meta), ChafaFormat::Unicode);
+        assert_eq!(pack_format(ChafaFormat::Kitty, meta), ChafaFormat::Kitty);
+        assert_eq!(pack_format(ChafaFormat::Auto, None), ChafaFormat::Auto);
+        assert_eq!(pack_colors(ChafaColors::Auto, meta), ChafaColors::C256);
+        assert_eq!(pack_colors(ChafaColors::C16, meta), ChafaColors::C16);
+    }
+
+    #[test]
+    fn pick_image_skips_recent_history() {
+        let images: Vec<PathBuf> = ["a.png", "b.png", "c.png"]
+            .iter()
+            .map(PathBuf::from)
+            .collect();
+        let recent = vec![PathBuf::from("a.png"), PathBuf::from("c.png")];
+        for seed in 0..10 {
+            let picked = pick_image(&images, &recent, Some(seed)).unwrap();
+            assert_eq!(picked, &PathBuf::from("b.png"));
+        }
+
+        let everything = images.clone();
+        assert!(pick_image(&images, &everything, None).is_ok());
+    }
+
+    #[test]
+    fn pool_packs_combines_images_and_messages() {
+        let mut a = test_pack("a");
+        a.messages
+            .insert(DEFAULT_CATEGORY.to_string(), vec!["from a".to_string()]);
+        let mut b = test_pack("b");
+        b.messages
+            .insert(DEFAULT_CATEGORY.to_string(), vec!["from b".to_string()]);
+
+        let pooled = pool_packs(&[a, b], None);
+        assert_eq!(
+            pooled.images,
+            vec![PathBuf::from("a.png"), PathBuf::from("b.png")]
+        );
+        assert_eq!(pooled.messages[DEFAULT_CATEGORY], vec!["from a", "from b"]);
+    }
+
+    #[test]
+    fn greeting_follows_locale_and_hour() {
+        assert_eq!(greeting_for("de_DE.UTF-8", 8), "Guten Morgen!");
+        assert_eq!(greeting_for("el_GR.UTF-8", 20), "Καλησπέρα!");
+        assert_eq!(greeting_for("fr", 14), "Bon après-midi !");
+        assert_eq!(greeting_for("C", 9), "Good morning!");
+        assert_eq!(greeting_for("", 23), "Good evening!");
+    }
+
+    #[test]
+    fn montage_picks_distinct_images_and_aligns_tiles() {
+        let images: Vec<PathBuf> = ["a.png", "b.png", "c.png", "d.png"]
+            .iter()
+            .map(PathBuf::from)
+            .collect();
+        let recent = vec![PathBuf::from("a.png")];
+        let picked = pick_images(&images, 3, &recent, Some(3)).unwrap();
+        assert_eq!(picked, pick_images(&images, 3, &recent, Some(3)).unwrap());
+        assert_eq!(picked.len(), 3);
+        assert!(!picked.contains(&recent[0]));
+        assert_eq!(pick_images(&images, 9, &recent, None).unwrap().len(), 4);
+
+        let tiles = vec!["\x1b[31mAB\nA\n".to_string(), "CC\nCC\nCC\n".to_string()];
+        let composed = compose_horizontal(&tiles, 1);
+        let lines: Vec<&str> = composed.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], "\x1b[31mAB\x1b[0m CC");
+        assert_eq!(lines[1], "A  CC");
+        assert_eq!(lines[2], "   CC");
+    }
+
+    #[test]
+    fn overlay_replaces_cells_and_keeps_colors() {
+        let image = "\x1b[31mAAAAAA\x1b[32mBB\n\x1b[34mCCCCCCCC\nDDDDDDDD\n";
+        let overlay = vec!["< hi >".to_string(), " --".to_string()];
+        let lines: Vec<String> = overlay_text(&overlay, image)
+            .lines()
+            .map(str::to_string)
+            .collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], "\x1b[0m< hi >\x1b[0m\x1b[31m\x1b[32mBB");
+        assert_eq!(lines[1], "\x1b[0m --   \x1b[0m\x1b[34mCC");
+        assert_eq!(lines[2], "DDDDDDDD");
+        assert!(lines.iter().all(|line| visible_width(line) == 8));
+
+        let wide = overlay_line("ab", 2, "x界yz");
+        assert_eq!(strip_ansi(&wide), "ab yz");
+
+        // The rendered image is what has to be wide enough, not the columns
+        // chafa was given.
+        assert!(overlay_fits(&overlay, image));
+        assert!(!overlay_fits(&overlay, "\x1b[31mBBB\x1b[0m\n"));
+        assert!(!overlay_fits(&overlay, ""));
+    }
+
+    #[test]
+    fn blocklist_filters_images_by_file_name() {
+        let mut pack = test_pack("blocky");
+        pack.images = ["a/keep.png", "b/skip.png", "c/skip_too.gif"]
+            .iter()
+            .map(PathBuf::from)
+            .collect();
+        let blocklist = build_globset(&["skip*".to_string()]).unwrap();
+        assert_eq!(
+            unblocked_images(&pack, blocklist.as_ref()),
+            vec![PathBuf::from("a/keep.png")]
+        );
+
+        let everything = build_globset(&["*.*".to_string()]).unwrap();
+        assert_eq!(unblocked_images(&pack, everything.as_ref()), pack.images);
+        assert_eq!(unblocked_images(&pack, None), pack.images);
+    }
+
+    #[test]
+    fn blocklist_matches_paths_inside_the_pack_and_weights_packs() {
+        let mut big = test_pack("big");
+        big.root = PathBuf::from("/packs/big");
+        big.images = (0..100)
+            .map(|i| big.root.join(format!("images/wip/{i}.png")))
+            .chain([big.root.join("images/done.png")])
+            .collect();
+        let blocklist = build_globset(&["images/wip/*".to_string()]).unwrap();
+        assert_eq!(
+            unblocked_images(&big, blocklist.as_ref()),
+            vec![PathBuf::from("/packs/big/images/done.png")]
+        );
+
+        let mut small = test_pack("small");
+        small.root = PathBuf::from("/packs/small");
+        small.images = vec![small.root.join("images/a.png")];
+        let packs = vec![big, small];
+        let big_picks = (0..50)
+            .filter(|&seed| {
+                resolve_pack(
+                    RANDOM_PACK,
+                    &packs,
+                    PackSelection::Weighted,
+                    Some(seed),
+                    blocklist.as_ref(),
+                )
+                .unwrap()
+                .unwrap()
+                .meta
+                .name
+                    == "big"
+            })
+            .count();
+        assert!((10..40).contains(&big_picks));
+        assert_eq!(pool_packs(&packs, blocklist.as_ref()).images.len(), 2);
+    }
+
+    #[test]
+    fn random_pack_is_deterministic_with_seed() {
+        let packs = vec![test_pack("a"), test_pack("b"), test_pack("c")];
+        let first = resolve_pack(RANDOM_PACK, &packs, PackSelection::Uniform, Some(7), None)
+            .unwrap()
+            .unwrap();
+        let second = resolve_pack(RANDOM_PACK, &packs, PackSelection::Uniform, Some(7), None)
+            .unwrap()
+            .unwrap();
+        assert_eq!(first.meta.name, second.meta.name);
+
+        let single = vec![test_pack("only")];
+        let pack = resolve_pack(RANDOM_PACK, &single, PackSelection::Uniform, None, None)
+            .unwrap()
+            .unwrap();
+        assert_eq!(pack.meta.name, "only");
+        assert!(
+            resolve_pack("missing", &packs, PackSelection::Uniform, None, None)
+                .unwrap()
+                .is_none()
+        );
+
+        let mut big = test_pack("big");
+        big.images = (0..100)
+            .map(|i| PathBuf::from(format!("{i}.png")))
+            .collect();
+        let packs = vec![big, test_pack("small")];
+        let big_picks = (0..50)
+            .filter(|&seed| {
+                resolve_pack(
+                    RANDOM_PACK,
+                    &packs,
+                    PackSelection::Weighted,
+                    Some(seed),
+                    None,
+                )
+                .unwrap()
+                .unwrap()
+                .meta
+                .name
+                    == "big"
+            })
+            .count();
+        assert!(big_picks > 40);
+
+        let mut hidden = test_pack("hidden");
+        hidden.meta.enabled = false;
+        let packs = vec![hidden, test_pack("shown")];
+        for seed in 0..20 {
+            let pack = resolve_pack(
+                RANDOM_PACK,
+                &packs,
+                PackSelection::Uniform,
+                Some(seed),
+                None,
+            )
+            .unwrap()
+            .unwrap();
+            assert_eq!(pack.meta.name, "shown");
+        }
+        assert!(
+            resolve_pack("hidden", &packs, PackSelection::Uniform, None, None)
+                .unwrap()
+                .is_some()
+        );
+    }
 }